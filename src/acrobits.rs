@@ -1,7 +1,81 @@
+use crate::errors::VoipBitsError;
+use crate::storage::Storage;
 use anyhow::Error;
-use fehler::throws;
+use chrono::{DateTime, Utc};
+use fehler::{throw, throws};
 use maplit::hashmap;
 use reqwest::Client;
+use serde::{
+    ser::{Error as _, SerializeStruct},
+    Deserialize, Serialize, Serializer,
+};
+use tracing::{info, warn};
+
+/// Content type of the messages carrying attachments, whose text is a `FileTransfer` JSON.
+pub const FILETRANSFER_CONTENT_TYPE: &str = "application/x-acro-filetransfer+json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileTransfer {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment {
+    #[serde(rename = "content-type")]
+    pub content_type: String,
+    #[serde(rename = "content-url")]
+    pub content_url: String,
+    #[serde(
+        rename = "content-size",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub content_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AcrobitsSMS {
+    pub sms_id: String,
+    pub sending_date: DateTime<Utc>,
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    pub sms_text: String,
+    pub attachments: Vec<Attachment>,
+}
+
+impl Serialize for AcrobitsSMS {
+    /// Messages with attachments are sent to Acrobits as a file transfer,
+    /// with the text moved into the file transfer body.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("AcrobitsSMS", 6)?;
+        state.serialize_field("sms_id", &self.sms_id)?;
+        state.serialize_field("sending_date", &self.sending_date)?;
+        if let Some(ref sender) = self.sender {
+            state.serialize_field("sender", sender)?;
+        }
+        if let Some(ref recipient) = self.recipient {
+            state.serialize_field("recipient", recipient)?;
+        }
+
+        if self.attachments.is_empty() {
+            state.serialize_field("sms_text", &self.sms_text)?;
+        } else {
+            let transfer = FileTransfer {
+                body: Some(self.sms_text.clone()).filter(|text| !text.is_empty()),
+                attachments: self.attachments.clone(),
+            };
+            let text = serde_json::to_string(&transfer).map_err(S::Error::custom)?;
+            state.serialize_field("content_type", FILETRANSFER_CONTENT_TYPE)?;
+            state.serialize_field("sms_text", &text)?;
+        }
+
+        state.end()
+    }
+}
 
 pub struct Acrobits {
     client: Client,
@@ -15,7 +89,7 @@ impl Acrobits {
     }
 
     #[throws(Error)]
-    #[tracing::instrument(skip(self))]
+    #[tracing::instrument(skip(self, device_token, message))]
     pub async fn notify(
         &self,
         appid: &str,
@@ -28,7 +102,8 @@ impl Acrobits {
             .post("https://pnm.cloudsoftphone.com/pnm2/send")
             .json(&hashmap! {
                 "verb" => "NotifyTextMessage",
-                // "Id" => "" // Voipms actually doesn't give us the messageid when notify us
+                // No "Id": a push also stands for a burst of messages, or reports on a
+                // scheduled one, which have no single message id
                 "Selector" => selector,
                 "Badge" => "1",
                 "UserName" => from,
@@ -39,4 +114,41 @@ impl Acrobits {
             .send()
             .await?;
     }
+
+    /// Pushes the message to every device registered for `did`, forgetting the
+    /// devices the push fails for.
+    #[throws(Error)]
+    pub async fn notify_devices(&self, storage: &Storage, did: &str, from: &str, message: &str) {
+        let tokens = match storage.get_tokens(did).await.map_err(VoipBitsError::from) {
+            Ok(tokens) => tokens,
+            Err(VoipBitsError::NoPushTokenAvailable(_)) => {
+                // Nobody to notify, there is no point to let the carrier retry.
+                info!("No device registered for {}, skipping", did);
+                return;
+            }
+            Err(e) => throw!(e),
+        };
+
+        let mut failed_tokens = vec![];
+        for token in tokens {
+            if let Err(e) = self
+                .notify(
+                    &token.appid,
+                    &token.push_token,
+                    &token.selector,
+                    from,
+                    message,
+                )
+                .await
+            {
+                warn!(
+                    "Notify device error: {:?}, removing the push token {}",
+                    e, token.push_token
+                );
+                failed_tokens.push(token);
+            }
+        }
+
+        storage.remove_tokens(did, &failed_tokens).await?;
+    }
 }
@@ -0,0 +1,152 @@
+//! With `ARCHIVE_MESSAGES`, every message seen through `/notify`, `/fetch` and `/send` is
+//! kept in the message log of the storage, so that a full sync still finds it once the
+//! carrier has dropped it (voip.ms keeps 90 days).
+
+use crate::acrobits::{AcrobitsSMS, Attachment};
+use crate::storage::Storage;
+use crate::voipms::guess_content_type;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::stream::{self, StreamExt};
+use std::collections::HashSet;
+use tracing::warn;
+
+/// How many messages are archived at once
+const CONCURRENCY: usize = 8;
+
+/// Archives the messages of the DID, a few at a time. Failing to archive does not fail the
+/// request.
+pub async fn record(storage: &Storage, did: &str, smss: &[AcrobitsSMS]) {
+    stream::iter(smss)
+        .for_each_concurrent(CONCURRENCY, |sms| async move {
+            if let Err(e) = storage.append_message(did, sms).await {
+                warn!(
+                    "[archive] Cannot archive {} of {}: {:#}",
+                    sms.sms_id, did, e
+                );
+            }
+        })
+        .await;
+}
+
+/// The messages of `live` not in `archived` yet, which a full sync archives.
+pub fn missing(live: &[AcrobitsSMS], archived: &[AcrobitsSMS]) -> Vec<AcrobitsSMS> {
+    let archived: HashSet<&str> = archived.iter().map(|sms| sms.sms_id.as_str()).collect();
+    live.iter()
+        .filter(|sms| !archived.contains(sms.sms_id.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// A message sent through `/send`, as the carrier took it.
+pub fn sent(id: &str, to: &str, text: &str, media: &[String]) -> AcrobitsSMS {
+    AcrobitsSMS {
+        sms_id: id.into(),
+        sending_date: Utc::now(),
+        sender: None,
+        recipient: Some(to.into()),
+        sms_text: text.into(),
+        attachments: media
+            .iter()
+            .map(|url| Attachment {
+                content_type: guess_content_type(url).into(),
+                content_url: url.clone(),
+                content_size: None,
+                filename: None,
+            })
+            .collect(),
+    }
+}
+
+/// A message received through `/notify`.
+pub fn received(id: &str, from: &str, text: &str) -> AcrobitsSMS {
+    AcrobitsSMS {
+        sms_id: id.into(),
+        sending_date: Utc::now(),
+        sender: Some(from.into()),
+        recipient: None,
+        sms_text: text.into(),
+        attachments: vec![],
+    }
+}
+
+/// Where a full sync reads the archive from.
+pub fn beginning() -> DateTime<Utc> {
+    Utc.timestamp_opt(0, 0).unwrap()
+}
+
+/// Adds the archived messages older than the live ones to `live`, oldest first.
+/// The live messages win, they are what the carrier holds now.
+pub fn merge(live: Vec<AcrobitsSMS>, archived: Vec<AcrobitsSMS>) -> Vec<AcrobitsSMS> {
+    let oldest_live = live.iter().map(|sms| sms.sending_date).min();
+    let mut seen: HashSet<String> = live.iter().map(|sms| sms.sms_id.clone()).collect();
+
+    let mut merged: Vec<_> = archived
+        .into_iter()
+        .filter(|sms| match oldest_live {
+            Some(oldest) => sms.sending_date < oldest,
+            None => true,
+        })
+        .filter(|sms| seen.insert(sms.sms_id.clone()))
+        .collect();
+    merged.extend(live);
+    merged.sort_by_key(|sms| sms.sending_date);
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn sms(id: &str, days_ago: i64) -> AcrobitsSMS {
+        AcrobitsSMS {
+            sms_id: id.into(),
+            sending_date: Utc.timestamp_opt(1_650_000_000, 0).unwrap() - Duration::days(days_ago),
+            sender: Some("5145550000".into()),
+            recipient: None,
+            sms_text: format!("text {}", id),
+            attachments: vec![],
+        }
+    }
+
+    fn ids(smss: &[AcrobitsSMS]) -> Vec<&str> {
+        smss.iter().map(|sms| sms.sms_id.as_str()).collect()
+    }
+
+    #[test]
+    fn older_history() {
+        let live = vec![sms("5", 80), sms("6", 1)];
+        let archived = vec![sms("1", 200), sms("2", 120), sms("2", 120), sms("5", 80)];
+
+        assert_eq!(ids(&merge(live, archived)), vec!["1", "2", "5", "6"]);
+    }
+
+    #[test]
+    fn live_wins() {
+        // Archived from `/send` a moment before the carrier dated it
+        let mut sent = sms("5", 80);
+        sent.sms_text = "archived".into();
+        sent.sending_date -= Duration::seconds(2);
+
+        let merged = merge(vec![sms("5", 80)], vec![sent]);
+        assert_eq!(ids(&merged), vec!["5"]);
+        assert_eq!(merged[0].sms_text, "text 5");
+    }
+
+    #[test]
+    fn only_missing() {
+        let live = vec![sms("5", 80), sms("6", 1)];
+        let archived = vec![sms("1", 200), sms("5", 80)];
+
+        assert_eq!(ids(&missing(&live, &archived)), vec!["6"]);
+    }
+
+    #[test]
+    fn nothing_live() {
+        assert_eq!(
+            ids(&merge(vec![], vec![sms("1", 200), sms("2", 120)])),
+            vec!["1", "2"]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! The operator commands next to `serve`.
+
+use crate::credential::{self, Credential, KeyRing, ProviderKind};
+use crate::export::{self, Format};
+use crate::{provider, storage, Opt};
+use anyhow::Error;
+use fehler::throws;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct KeygenArgs {
+    #[structopt(long, default_value = "2048")]
+    bits: usize,
+
+    /// Id of the new key, see `KEY_ID`
+    #[structopt(long, default_value = "1")]
+    key_id: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EncryptCredArgs {
+    /// The server public key, base64 of the DER SubjectPublicKeyInfo
+    #[structopt(long, env)]
+    public_key: String,
+
+    /// Id of the server key the blob is made for
+    #[structopt(long, env, default_value = "1")]
+    key_id: String,
+
+    /// voipms, twilio or telnyx
+    #[structopt(long, default_value = "voipms")]
+    provider: ProviderKind,
+
+    did: String,
+
+    /// voip.ms: the account email, Twilio: the account SID, Telnyx: the messaging profile id
+    account: String,
+
+    /// Print the token of `/provision/<token>` instead of the blob
+    #[structopt(long)]
+    token: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DecryptCredArgs {
+    #[structopt(flatten)]
+    keys: KeyRing,
+
+    /// Print the password or API key too, instead of masking it
+    #[structopt(long)]
+    show_secret: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExportArgs {
+    /// The server settings, for the keys, the storage and `ARCHIVE_MESSAGES`
+    #[structopt(flatten)]
+    opt: Opt,
+
+    /// csv, ndjson or mbox
+    #[structopt(long, default_value = "csv")]
+    format: Format,
+
+    /// The file to write, stdout by default
+    #[structopt(long, short)]
+    output: Option<PathBuf>,
+}
+
+/// Prints the keypair in the form of the environment variables.
+#[throws(Error)]
+pub fn keygen(args: KeygenArgs) {
+    let (private_key, public_key) = credential::generate_keypair(args.bits)?;
+
+    println!("PRIVATE_KEY={}", private_key);
+    println!("PUBLIC_KEY={}", public_key);
+    println!("KEY_ID={}", args.key_id);
+}
+
+/// Reads the password (or API key) from stdin, so that it stays out of the shell history.
+#[throws(Error)]
+pub fn encrypt_cred(args: EncryptCredArgs) {
+    let public_key = credential::load_public_key(&args.public_key)?;
+    let secret = read_line("Password or API key: ")?;
+
+    let cred = Credential {
+        provider: args.provider,
+        did: args.did,
+        account: args.account,
+        secret,
+    };
+    let blob = cred.encrypt(&public_key, &args.key_id)?;
+    if args.token {
+        println!("{}", credential::to_token(&blob));
+    } else {
+        println!("{}", blob);
+    }
+}
+
+/// Reads the blob from stdin. It takes the server private keys, so only operators can run it.
+#[throws(Error)]
+pub fn decrypt_cred(args: DecryptCredArgs) {
+    let blob = read_line("Credential blob: ")?;
+    let cred = Credential::decrypt(&args.keys, &blob)?;
+
+    println!("provider: {:?}", cred.provider);
+    println!("did: {}", cred.did);
+    println!("account: {}", cred.account);
+    if args.show_secret {
+        println!("secret: {}", cred.secret);
+    } else {
+        println!("secret: <{} chars>", cred.secret.chars().count());
+    }
+}
+
+/// Reads the blob from stdin, like `decrypt_cred`.
+#[throws(Error)]
+pub async fn export(args: ExportArgs) {
+    let blob = read_line("Credential blob: ")?;
+    let storage = storage::connect(&args.opt).await?;
+    let provider = provider::from_cred(&args.opt, &storage, &blob)?;
+
+    let smss = export::collect(&storage, provider.as_ref(), args.opt.archive_messages).await?;
+    match args.output {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(&path)?);
+            export::write(args.format, provider.did(), &smss, &mut out)?;
+            out.flush()?;
+            eprintln!("{} messages written to {}", smss.len(), path.display());
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            export::write(args.format, provider.did(), &smss, &mut out)?;
+            out.flush()?;
+        }
+    }
+}
+
+#[throws(Error)]
+fn read_line(prompt: &str) -> String {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    line.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
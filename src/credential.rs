@@ -0,0 +1,697 @@
+//! The credential blobs the softphones send with every request.
+//!
+//! A blob is an envelope around the credential, encrypted to the server key:
+//!
+//! * v1: base64 of the RSA PKCS#1 v1.5 encrypted `Credential::parse` text, optionally
+//!   prefixed with `v1:<key id>:`. The text has to fit in a single RSA block, so long
+//!   passwords don't.
+//! * v2: `v2:[<key id>:]<key>:<nonce>:<ciphertext>`, all base64. `key` is a random AES-256
+//!   key wrapped with RSA-OAEP-SHA256, and `ciphertext` the AES-GCM encrypted credential JSON.
+//!
+//! The server can hold several keys, see `KeyRing`. Blobs without a key id are tried
+//! with each of them.
+
+use crate::errors::VoipBitsError;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use rand::{rngs::OsRng, RngCore};
+use rsa::{BigUint, PaddingScheme, PublicKey, PublicKeyParts, RSAPrivateKey, RSAPublicKey};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    VoipMS,
+    Twilio,
+    Telnyx,
+}
+
+impl FromStr for ProviderKind {
+    type Err = VoipBitsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "voipms" => Ok(ProviderKind::VoipMS),
+            "twilio" => Ok(ProviderKind::Twilio),
+            "telnyx" => Ok(ProviderKind::Telnyx),
+            _ => Err(VoipBitsError::MalformedCredential(format!(
+                "unknown provider {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// The decrypted credential of a DID.
+///
+/// The plaintext is `<provider>:<did>:<account>:<secret>`. Credentials made before
+/// other carriers were supported are `<did>:<account>:<password>` and belong to voip.ms.
+/// In v2 envelopes it is the JSON of this struct.
+#[derive(Serialize, Deserialize)]
+pub struct Credential {
+    pub provider: ProviderKind,
+    pub did: String,
+    /// voip.ms: the API username (account email), Twilio: the account SID,
+    /// Telnyx: the messaging profile id
+    pub account: String,
+    /// voip.ms: the API password, Twilio: the auth token, Telnyx: the API key
+    pub secret: String,
+}
+
+impl Credential {
+    #[throws(Error)]
+    pub fn decrypt(keys: &KeyRing, cred: &str) -> Credential {
+        // '+' becomes ' ' when the blob is sent unescaped in a form body
+        let cred = cred.trim().replace(" ", "+");
+        let (kid, envelope) = Envelope::parse(&cred)?;
+        let (id, cred) = keys.open(kid, &envelope)?;
+
+        if id != keys.key_id {
+            // Counted by a log metric filter, to know when a retired key can go
+            info!("[metric] retired_key_used key_id={} did={}", id, cred.did);
+        }
+        cred
+    }
+
+    /// Makes a v2 blob of the credential for the server key `key_id`.
+    #[throws(Error)]
+    pub fn encrypt(&self, public_key: &RSAPublicKey, key_id: &str) -> String {
+        if key_id.contains(':') || key_id.contains(',') {
+            throw!(anyhow!("key id {:?} cannot contain ':' or ','", key_id));
+        }
+
+        let mut key = [0u8; 32];
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow!("invalid AES key length"))?
+            .encrypt(&Nonce::from(nonce), serde_json::to_vec(self)?.as_slice())
+            .map_err(|_| anyhow!("failed to encrypt the credential"))?;
+        let key =
+            public_key.encrypt(&mut OsRng, PaddingScheme::new_oaep::<sha2::Sha256>(), &key)?;
+
+        format!(
+            "v2:{}:{}:{}:{}",
+            key_id,
+            base64::encode(key),
+            base64::encode(nonce),
+            base64::encode(ciphertext)
+        )
+    }
+
+    #[throws(VoipBitsError)]
+    pub fn parse(plain: &str) -> Credential {
+        let tag = plain.split(':').next().unwrap_or("");
+        if tag.chars().all(|c| c.is_ascii_digit()) {
+            // Legacy voip.ms credential starting with the DID
+            return match plain.splitn(3, ':').collect::<Vec<_>>().as_slice() {
+                [did, account, secret] => Credential {
+                    provider: ProviderKind::VoipMS,
+                    did: did.to_string(),
+                    account: account.to_string(),
+                    secret: secret.to_string(),
+                },
+                parts => throw!(VoipBitsError::MalformedCredential(format!(
+                    "expected <did>:<account>:<password>, got {} fields",
+                    parts.len()
+                ))),
+            };
+        }
+
+        match plain.splitn(4, ':').collect::<Vec<_>>().as_slice() {
+            [provider, did, account, secret] => Credential {
+                provider: provider.parse()?,
+                did: did.to_string(),
+                account: account.to_string(),
+                secret: secret.to_string(),
+            },
+            parts => throw!(VoipBitsError::MalformedCredential(format!(
+                "expected <provider>:<did>:<account>:<secret>, got {} fields",
+                parts.len()
+            ))),
+        }
+    }
+}
+
+/// The server keys: the current one, which the new blobs are made for, and the retired ones,
+/// which are kept until the softphones provisioned with them are gone.
+#[derive(Debug, Clone, StructOpt)]
+pub struct KeyRing {
+    /// The current server key, base64 of the PKCS#8 DER
+    #[structopt(env)]
+    private_key: PrivateKey,
+
+    /// Id of the current key, written into the blobs made for it
+    #[structopt(env, default_value = "1")]
+    key_id: String,
+
+    /// Comma separated `<key id>:<private key>` of the retired keys
+    #[structopt(env, default_value = "")]
+    retired_keys: RetiredKeys,
+}
+
+impl KeyRing {
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The current public key, in the `PUBLIC_KEY` form.
+    pub fn public_key(&self) -> String {
+        base64::encode(public_key_der(&self.private_key.0.to_public_key()))
+    }
+
+    /// Opens the envelope, returning the id of the key it was made for.
+    #[throws(Error)]
+    fn open(&self, kid: Option<&str>, envelope: &Envelope) -> (&str, Credential) {
+        let mut errors = vec![];
+        let opened =
+            self.candidates(kid)
+                .into_iter()
+                .find_map(|(id, key)| match envelope.open(key) {
+                    Ok(cred) => Some((id, cred)),
+                    Err(e) => {
+                        errors.push(e);
+                        None
+                    }
+                });
+
+        match opened {
+            Some(opened) => opened,
+            // The error of the first candidate, which is the most likely key
+            None => throw!(errors.swap_remove(0)),
+        }
+    }
+
+    /// The keys that may open a blob, the current key first. A blob for a key id the
+    /// server does not know, such as a key renamed on rotation, is tried with them all.
+    fn candidates(&self, kid: Option<&str>) -> Vec<(&str, &RSAPrivateKey)> {
+        let keys: Vec<_> = std::iter::once((self.key_id.as_str(), &self.private_key.0))
+            .chain(
+                self.retired_keys
+                    .0
+                    .iter()
+                    .map(|(id, key)| (id.as_str(), &key.0)),
+            )
+            .collect();
+
+        match kid {
+            Some(kid) if keys.iter().any(|(id, _)| *id == kid) => {
+                keys.into_iter().filter(|(id, _)| *id == kid).collect()
+            }
+            _ => keys,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PrivateKey(RSAPrivateKey);
+
+impl FromStr for PrivateKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        base64::decode(s.trim())
+            .map_err(Error::from)
+            .and_then(|key| Ok(RSAPrivateKey::from_pkcs8(&key)?))
+            .map(PrivateKey)
+            .map_err(|e| format!("invalid private key: {}", e))
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey(***)")
+    }
+}
+
+/// Parses the base64 of the DER SubjectPublicKeyInfo, the `PUBLIC_KEY` form.
+#[throws(Error)]
+pub fn load_public_key(public_key: &str) -> RSAPublicKey {
+    RSAPublicKey::from_pkcs8(&base64::decode(public_key.trim())?)
+        .map_err(|e| anyhow!("invalid public key: {}", e))?
+}
+
+/// Generates a server keypair, returning the base64 of the PKCS#8 private key
+/// and of the SubjectPublicKeyInfo public key.
+#[throws(Error)]
+pub fn generate_keypair(bits: usize) -> (String, String) {
+    let key = RSAPrivateKey::new(&mut OsRng, bits)?;
+    let (p, q) = match key.primes() {
+        [p, q] => (p, q),
+        _ => throw!(anyhow!("expected a two prime key")),
+    };
+    let one = BigUint::from(1u32);
+    let two = BigUint::from(2u32);
+
+    // RSAPrivateKey from PKCS#1, q^-1 mod p by Fermat since p is prime
+    let pkcs1 = der::sequence(&[
+        der::integer(&BigUint::from(0u32)),
+        der::integer(key.n()),
+        der::integer(key.e()),
+        der::integer(key.d()),
+        der::integer(p),
+        der::integer(q),
+        der::integer(&(key.d() % (p - &one))),
+        der::integer(&(key.d() % (q - &one))),
+        der::integer(&q.modpow(&(p - &two), p)),
+    ]);
+    let private_key = der::sequence(&[
+        der::integer(&BigUint::from(0u32)),
+        der::RSA_ALGORITHM.to_vec(),
+        der::octet_string(&pkcs1),
+    ]);
+
+    (
+        base64::encode(private_key),
+        base64::encode(public_key_der(&key.to_public_key())),
+    )
+}
+
+/// The DER SubjectPublicKeyInfo of the key.
+fn public_key_der(key: &RSAPublicKey) -> Vec<u8> {
+    let public_key = der::sequence(&[der::integer(key.n()), der::integer(key.e())]);
+    der::sequence(&[der::RSA_ALGORITHM.to_vec(), der::bit_string(&public_key)])
+}
+
+/// Just enough DER to write RSA keys, which rsa 0.3 can read but not write.
+mod der {
+    use rsa::BigUint;
+
+    /// AlgorithmIdentifier of rsaEncryption (1.2.840.113549.1.1.1) with NULL parameters
+    pub const RSA_ALGORITHM: &[u8] = &[
+        0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+    ];
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        let len = content.len();
+        if len < 0x80 {
+            out.push(len as u8);
+        } else {
+            let bytes: Vec<u8> = len
+                .to_be_bytes()
+                .iter()
+                .copied()
+                .skip_while(|b| *b == 0)
+                .collect();
+            out.push(0x80 | bytes.len() as u8);
+            out.extend(bytes);
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    pub fn integer(n: &BigUint) -> Vec<u8> {
+        let mut bytes = n.to_bytes_be();
+        if bytes[0] & 0x80 != 0 {
+            bytes.insert(0, 0);
+        }
+        tlv(0x02, &bytes)
+    }
+
+    pub fn octet_string(content: &[u8]) -> Vec<u8> {
+        tlv(0x04, content)
+    }
+
+    pub fn bit_string(content: &[u8]) -> Vec<u8> {
+        let mut bits = vec![0];
+        bits.extend_from_slice(content);
+        tlv(0x03, &bits)
+    }
+
+    pub fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+        tlv(0x30, &items.concat())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetiredKeys(Vec<(String, PrivateKey)>);
+
+impl FromStr for RetiredKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once(':') {
+                Some((id, key)) => Ok((id.to_string(), key.parse()?)),
+                None => Err(format!("expected <key id>:<private key>, got {}", entry)),
+            })
+            .collect::<Result<_, _>>()
+            .map(RetiredKeys)
+    }
+}
+
+enum Envelope<'a> {
+    V1(&'a str),
+    V2 {
+        key: &'a str,
+        nonce: &'a str,
+        ciphertext: &'a str,
+    },
+}
+
+impl<'a> Envelope<'a> {
+    /// Splits the blob into its key id, if any, and the envelope.
+    #[throws(VoipBitsError)]
+    fn parse(cred: &'a str) -> (Option<&'a str>, Envelope<'a>) {
+        match cred.split(':').collect::<Vec<_>>().as_slice() {
+            [blob] => (None, Envelope::V1(blob)),
+            ["v1", kid, blob] => (Some(*kid), Envelope::V1(blob)),
+            ["v2", key, nonce, ciphertext] => (
+                None,
+                Envelope::V2 {
+                    key,
+                    nonce,
+                    ciphertext,
+                },
+            ),
+            ["v2", kid, key, nonce, ciphertext] => (
+                Some(*kid),
+                Envelope::V2 {
+                    key,
+                    nonce,
+                    ciphertext,
+                },
+            ),
+            [version, ..] => throw!(VoipBitsError::Decryption(format!(
+                "unknown credential envelope {}",
+                version
+            ))),
+            [] => unreachable!("split gives at least one part"),
+        }
+    }
+
+    #[throws(VoipBitsError)]
+    fn open(&self, priv_key: &RSAPrivateKey) -> Credential {
+        match *self {
+            Envelope::V1(blob) => Credential::parse(&decrypt_v1(priv_key, blob)?)?,
+            Envelope::V2 {
+                key,
+                nonce,
+                ciphertext,
+            } => {
+                let plain = decrypt_v2(priv_key, key, nonce, ciphertext)?;
+                serde_json::from_slice(&plain)
+                    .map_err(|e| VoipBitsError::MalformedCredential(e.to_string()))?
+            }
+        }
+    }
+}
+
+/// The URL-safe form of a blob, for the provisioning URLs.
+pub fn to_token(blob: &str) -> String {
+    base64::encode_config(blob, base64::URL_SAFE_NO_PAD)
+}
+
+#[throws(VoipBitsError)]
+pub fn from_token(token: &str) -> String {
+    base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|blob| String::from_utf8(blob).ok())
+        .ok_or_else(|| VoipBitsError::Decryption("malformed provisioning token".into()))?
+}
+
+#[throws(VoipBitsError)]
+fn decode(part: &str) -> Vec<u8> {
+    base64::decode(part)
+        .map_err(|e| VoipBitsError::Decryption(format!("credential is not base64: {}", e)))?
+}
+
+/// The legacy envelope: the credential text encrypted with PKCS#1 v1.5.
+#[throws(VoipBitsError)]
+fn decrypt_v1(priv_key: &RSAPrivateKey, cred: &str) -> String {
+    let cred = priv_key
+        .decrypt(PaddingScheme::new_pkcs1v15_encrypt(), &decode(cred)?)
+        .map_err(|e| VoipBitsError::Decryption(e.to_string()))?;
+
+    String::from_utf8(cred)
+        .map_err(|_| VoipBitsError::MalformedCredential("credential is not UTF-8".into()))?
+}
+
+/// The hybrid envelope, returning the credential JSON.
+#[throws(VoipBitsError)]
+fn decrypt_v2(priv_key: &RSAPrivateKey, key: &str, nonce: &str, ciphertext: &str) -> Vec<u8> {
+    let (key, nonce, ciphertext) = (decode(key)?, decode(nonce)?, decode(ciphertext)?);
+
+    let key = priv_key
+        .decrypt(PaddingScheme::new_oaep::<sha2::Sha256>(), &key)
+        .map_err(|e| VoipBitsError::Decryption(e.to_string()))?;
+    let cipher = Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| VoipBitsError::Decryption("expected an AES-256 key".into()))?;
+    let nonce: [u8; 12] = nonce
+        .try_into()
+        .map_err(|_| VoipBitsError::Decryption("expected a 96 bit nonce".into()))?;
+
+    cipher
+        .decrypt(&Nonce::from(nonce), ciphertext.as_slice())
+        .map_err(|_| VoipBitsError::Decryption("credential failed authentication".into()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    /// Generating keys is slow, the tests share a few.
+    fn test_key(i: usize) -> &'static RSAPrivateKey {
+        static KEYS: OnceLock<Vec<RSAPrivateKey>> = OnceLock::new();
+        &KEYS.get_or_init(|| {
+            (0..3)
+                .map(|_| RSAPrivateKey::new(&mut OsRng, 1024).unwrap())
+                .collect()
+        })[i]
+    }
+
+    fn keyring() -> KeyRing {
+        KeyRing {
+            private_key: PrivateKey(test_key(0).clone()),
+            key_id: "1".into(),
+            retired_keys: RetiredKeys(vec![]),
+        }
+    }
+
+    /// Key 2 is current, key 1 retired, and key 0 was dropped.
+    fn rotated() -> KeyRing {
+        KeyRing {
+            private_key: PrivateKey(test_key(2).clone()),
+            key_id: "2".into(),
+            retired_keys: RetiredKeys(vec![("1".into(), PrivateKey(test_key(1).clone()))]),
+        }
+    }
+
+    /// Opens the blob with `keys`, returning the id of the key that did.
+    fn opened_with(keys: &KeyRing, blob: &str) -> String {
+        let (kid, envelope) = Envelope::parse(blob).unwrap();
+        let (id, cred) = keys.open(kid, &envelope).unwrap();
+        assert_eq!(cred.did, "15145551111");
+        id.to_string()
+    }
+
+    fn credential() -> Credential {
+        Credential {
+            provider: ProviderKind::Twilio,
+            did: "15145551111".into(),
+            account: "AC123".into(),
+            secret: "s3cr:et".into(),
+        }
+    }
+
+    fn v1_blob(key: &RSAPrivateKey, plain: &str) -> String {
+        let encrypted = key
+            .to_public_key()
+            .encrypt(
+                &mut OsRng,
+                PaddingScheme::new_pkcs1v15_encrypt(),
+                plain.as_bytes(),
+            )
+            .unwrap();
+        base64::encode(encrypted)
+    }
+
+    /// Credentials have no `Debug`, which would show the secret.
+    fn rejection(keys: &KeyRing, blob: &str) -> Error {
+        match Credential::decrypt(keys, blob) {
+            Ok(_) => panic!("{} opened", blob),
+            Err(e) => e,
+        }
+    }
+
+    fn is_decryption_error(e: &Error) -> bool {
+        matches!(
+            e.downcast_ref::<VoipBitsError>(),
+            Some(VoipBitsError::Decryption(_))
+        )
+    }
+
+    #[test]
+    fn v1() {
+        let keys = keyring();
+        let blob = v1_blob(test_key(0), "5145551111:me@example.com:pass:word");
+
+        for blob in &[blob.clone(), format!("v1:1:{}", blob)] {
+            let cred = Credential::decrypt(&keys, blob).unwrap();
+            assert_eq!(cred.provider, ProviderKind::VoipMS);
+            assert_eq!(cred.did, "5145551111");
+            assert_eq!(cred.account, "me@example.com");
+            assert_eq!(cred.secret, "pass:word");
+        }
+
+        // Sent unescaped in a form body
+        let spaced = blob.replace('+', " ");
+        assert_eq!(
+            Credential::decrypt(&keys, &spaced).unwrap().did,
+            "5145551111"
+        );
+
+        let truncated = &blob[..blob.len() / 2];
+        assert!(is_decryption_error(&rejection(&keys, truncated)));
+    }
+
+    #[test]
+    fn v2() {
+        let keys = keyring();
+        let blob = credential()
+            .encrypt(&test_key(0).to_public_key(), "1")
+            .unwrap();
+
+        let cred = Credential::decrypt(&keys, &blob).unwrap();
+        assert_eq!(cred.provider, ProviderKind::Twilio);
+        assert_eq!(cred.did, "15145551111");
+        assert_eq!(cred.account, "AC123");
+        assert_eq!(cred.secret, "s3cr:et");
+
+        // Without the key id
+        let parts: Vec<_> = blob.split(':').collect();
+        let anonymous = format!("v2:{}:{}:{}", parts[2], parts[3], parts[4]);
+        assert_eq!(
+            Credential::decrypt(&keys, &anonymous).unwrap().did,
+            "15145551111"
+        );
+    }
+
+    #[test]
+    fn v2_rejected() {
+        let keys = keyring();
+        let blob = credential()
+            .encrypt(&test_key(0).to_public_key(), "1")
+            .unwrap();
+        let parts: Vec<_> = blob.split(':').collect();
+
+        // A ciphertext cut short or altered fails the AES-GCM authentication
+        let mut ciphertext = base64::decode(parts[4]).unwrap();
+        ciphertext.truncate(ciphertext.len() - 1);
+        let truncated = format!(
+            "v2:1:{}:{}:{}",
+            parts[2],
+            parts[3],
+            base64::encode(&ciphertext)
+        );
+        ciphertext.push(0);
+        ciphertext[0] ^= 1;
+        let tampered = format!(
+            "v2:1:{}:{}:{}",
+            parts[2],
+            parts[3],
+            base64::encode(&ciphertext)
+        );
+
+        let short_nonce = format!(
+            "v2:1:{}:{}:{}",
+            parts[2],
+            base64::encode([0u8; 8]),
+            parts[4]
+        );
+        let missing_part = format!("v2:1:{}:{}", parts[2], parts[3]);
+
+        for blob in &[truncated, tampered, short_nonce, missing_part] {
+            rejection(&keys, blob);
+        }
+    }
+
+    #[test]
+    fn unknown_version() {
+        let keys = keyring();
+        let blob = credential()
+            .encrypt(&test_key(0).to_public_key(), "1")
+            .unwrap();
+
+        let v3 = blob.replacen("v2:", "v3:", 1);
+        assert!(is_decryption_error(&rejection(&keys, &v3)));
+    }
+
+    #[test]
+    fn current_key() {
+        let blob = credential()
+            .encrypt(&test_key(2).to_public_key(), "2")
+            .unwrap();
+        assert_eq!(opened_with(&rotated(), &blob), "2");
+    }
+
+    #[test]
+    fn retired_key() {
+        let blob = credential()
+            .encrypt(&test_key(1).to_public_key(), "1")
+            .unwrap();
+        assert_eq!(opened_with(&rotated(), &blob), "1");
+
+        // Counted by `[metric] retired_key_used`
+        assert_eq!(
+            Credential::decrypt(&rotated(), &blob).unwrap().did,
+            "15145551111"
+        );
+    }
+
+    #[test]
+    fn unknown_key_id() {
+        let blob = credential()
+            .encrypt(&test_key(1).to_public_key(), "old")
+            .unwrap();
+        assert_eq!(rotated().candidates(Some("old")).len(), 2);
+        assert_eq!(opened_with(&rotated(), &blob), "1");
+
+        let v1 = format!("v1:old:{}", v1_blob(test_key(2), "5145551111:me:pass"));
+        assert_eq!(
+            Credential::decrypt(&rotated(), &v1).unwrap().did,
+            "5145551111"
+        );
+    }
+
+    #[test]
+    fn removed_key() {
+        for kid in &["1", "0"] {
+            let blob = credential()
+                .encrypt(&test_key(0).to_public_key(), kid)
+                .unwrap();
+            assert!(is_decryption_error(&rejection(&rotated(), &blob)));
+        }
+    }
+
+    #[test]
+    fn generated_keypair() {
+        let (private_key, public_key) = generate_keypair(1024).unwrap();
+        let private_key: PrivateKey = private_key.parse().unwrap();
+        let public_key = load_public_key(&public_key).unwrap();
+        assert_eq!(public_key, private_key.0.to_public_key());
+
+        let keys = KeyRing {
+            private_key,
+            key_id: "1".into(),
+            retired_keys: RetiredKeys(vec![]),
+        };
+        assert_eq!(load_public_key(&keys.public_key()).unwrap(), public_key);
+
+        let blob = credential().encrypt(&public_key, "1").unwrap();
+        assert_eq!(Credential::decrypt(&keys, &blob).unwrap().secret, "s3cr:et");
+    }
+}
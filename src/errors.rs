@@ -1,13 +1,115 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
 use thiserror::Error;
+use tracing::{error, warn};
 
 #[derive(Error, Debug)]
 pub enum VoipBitsError {
+    #[error("Cannot decrypt the credential: {0}")]
+    Decryption(String),
+    #[error("Malformed credential: {0}")]
+    MalformedCredential(String),
+    #[error("Invalid callback signature for {0}")]
+    InvalidSignature(String),
+    #[error("Carrier API error: {0}")]
+    Upstream(String),
+    #[error("Carrier temporarily unavailable: {0}")]
+    Unavailable(String),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("Invalid request: {0}")]
+    Validation(String),
     #[error("Empty message")]
     EmptyMessage,
     #[error("Invalid number: {0}")]
     InvalidNumber(String),
+    #[error("Cannot send SMS to {0}")]
+    UndeliverableNumber(String),
     #[error("No such SMS with id {0}")]
     NoSuchSMS(String),
     #[error("No push token available for {0}")]
     NoPushTokenAvailable(String),
+    #[error("A message with idempotency key {0} is still being sent")]
+    SendInProgress(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl VoipBitsError {
+    /// A stable, machine readable identifier for the error kind.
+    pub fn code(&self) -> &'static str {
+        use VoipBitsError::*;
+
+        match self {
+            Decryption(_) => "decryption_failed",
+            MalformedCredential(_) => "malformed_credential",
+            InvalidSignature(_) => "invalid_signature",
+            Upstream(_) => "upstream_error",
+            Unavailable(_) => "upstream_unavailable",
+            Storage(_) => "storage_error",
+            Validation(_) => "validation_error",
+            EmptyMessage => "empty_message",
+            InvalidNumber(_) => "invalid_number",
+            UndeliverableNumber(_) => "undeliverable_number",
+            NoSuchSMS(_) => "no_such_sms",
+            NoPushTokenAvailable(_) => "no_push_token",
+            SendInProgress(_) => "send_in_progress",
+            Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        use VoipBitsError::*;
+
+        match self {
+            Decryption(_) => StatusCode::UNAUTHORIZED,
+            InvalidSignature(_) => StatusCode::FORBIDDEN,
+            MalformedCredential(_)
+            | Validation(_)
+            | EmptyMessage
+            | InvalidNumber(_)
+            | UndeliverableNumber(_) => StatusCode::BAD_REQUEST,
+            NoSuchSMS(_) | NoPushTokenAvailable(_) => StatusCode::NOT_FOUND,
+            SendInProgress(_) => StatusCode::CONFLICT,
+            Upstream(_) => StatusCode::BAD_GATEWAY,
+            Unavailable(_) | Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<anyhow::Error> for VoipBitsError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<VoipBitsError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+
+        match e.downcast::<reqwest::Error>() {
+            Ok(e) => VoipBitsError::Upstream(e.to_string()),
+            Err(e) => VoipBitsError::Internal(format!("{:#}", e)),
+        }
+    }
+}
+
+impl IntoResponse for VoipBitsError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!("[error] ({}) {}", status, self);
+        } else {
+            warn!("[error] ({}) {}", status, self);
+        }
+
+        let body = json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+
+        (status, Json(body)).into_response()
+    }
 }
@@ -0,0 +1,390 @@
+//! Exports of the message history of a DID, for keeping records: everything the carrier
+//! still has, plus the archive with `ARCHIVE_MESSAGES`, in both directions.
+//!
+//! Dates are in UTC whatever the timezone of the carrier account. CSV and NDJSON have a
+//! row per message, mbox an email per message threaded by contact.
+
+use crate::acrobits::AcrobitsSMS;
+use crate::archive;
+use crate::provider::SmsProvider;
+use crate::storage::Storage;
+use anyhow::Error;
+use chrono::{DateTime, SecondsFormat, Utc};
+use fehler::throws;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The domain of the email addresses and message ids of mbox exports
+const MAIL_DOMAIN: &str = "sms.voipbits.invalid";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Ndjson,
+    Mbox,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Mbox => "application/mbox",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Mbox => "mbox",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            "mbox" => Ok(Format::Mbox),
+            _ => Err(format!(
+                "unknown export format {}, expecting one of csv, ndjson or mbox",
+                s
+            )),
+        }
+    }
+}
+
+/// Every message of the DID of `provider`, oldest first, with those of the archive when
+/// `archived` (`ARCHIVE_MESSAGES`). Fails unless the carrier confirms the DID, since the
+/// archive is only keyed by the DID of the credential.
+#[throws(Error)]
+pub async fn collect(
+    storage: &Storage,
+    provider: &dyn SmsProvider,
+    archived: bool,
+) -> Vec<AcrobitsSMS> {
+    provider.verify().await?;
+    let live = provider.fetch_from_date(None).await?;
+
+    if archived {
+        let archived = storage
+            .list_messages(provider.did(), archive::beginning())
+            .await?;
+        archive::merge(live, archived)
+    } else {
+        let mut smss = live;
+        smss.sort_by_key(|sms| sms.sending_date);
+        smss
+    }
+}
+
+/// A message as exported to CSV and NDJSON.
+#[derive(Serialize, Debug)]
+struct Record<'a> {
+    id: &'a str,
+    date: String,
+    direction: &'static str,
+    did: &'a str,
+    contact: &'a str,
+    text: &'a str,
+    attachments: Vec<&'a str>,
+}
+
+impl<'a> Record<'a> {
+    fn new(did: &'a str, sms: &'a AcrobitsSMS) -> Self {
+        Record {
+            id: &sms.sms_id,
+            date: utc(sms.sending_date),
+            direction: if is_sent(sms) { "sent" } else { "received" },
+            did,
+            contact: contact(sms),
+            text: &sms.sms_text,
+            attachments: sms
+                .attachments
+                .iter()
+                .map(|attachment| attachment.content_url.as_str())
+                .collect(),
+        }
+    }
+}
+
+/// Writes the messages of `did` in `format`.
+#[throws(Error)]
+pub fn write<W: Write>(format: Format, did: &str, smss: &[AcrobitsSMS], out: &mut W) {
+    match format {
+        Format::Csv => write_csv(did, smss, out)?,
+        Format::Ndjson => {
+            for sms in smss {
+                serde_json::to_writer(&mut *out, &Record::new(did, sms))?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Format::Mbox => write_mbox(did, smss, out)?,
+    }
+}
+
+/// RFC 4180, attachments separated by spaces.
+#[throws(Error)]
+fn write_csv<W: Write>(did: &str, smss: &[AcrobitsSMS], out: &mut W) {
+    out.write_all(b"id,date,direction,did,contact,text,attachments\r\n")?;
+
+    for sms in smss {
+        let record = Record::new(did, sms);
+        let attachments = record.attachments.join(" ");
+        let fields = [
+            record.id,
+            &record.date,
+            record.direction,
+            record.did,
+            record.contact,
+            record.text,
+            &attachments,
+        ];
+
+        let row: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+        write!(out, "{}\r\n", row.join(","))?;
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// An email per message. The messages with a contact reply to the first one, so that
+/// mail clients show a thread per contact.
+#[throws(Error)]
+fn write_mbox<W: Write>(did: &str, smss: &[AcrobitsSMS], out: &mut W) {
+    let mut threads: BTreeMap<&str, Vec<&AcrobitsSMS>> = BTreeMap::new();
+    for sms in smss {
+        threads.entry(contact(sms)).or_default().push(sms);
+    }
+
+    for (contact, mut thread) in threads {
+        thread.sort_by_key(|sms| sms.sending_date);
+        let root = message_id(did, &thread[0].sms_id);
+
+        for (i, sms) in thread.iter().enumerate() {
+            let (from, to) = if is_sent(sms) {
+                (did, contact)
+            } else {
+                (contact, did)
+            };
+
+            writeln!(
+                out,
+                "From {} {}",
+                address(from),
+                sms.sending_date.format("%a %b %e %H:%M:%S %Y")
+            )?;
+            writeln!(out, "From: {}", address(from))?;
+            writeln!(out, "To: {}", address(to))?;
+            writeln!(out, "Date: {}", sms.sending_date.to_rfc2822())?;
+            writeln!(out, "Subject: SMS with {}", contact)?;
+            writeln!(out, "Message-ID: {}", message_id(did, &sms.sms_id))?;
+            if i > 0 {
+                writeln!(out, "In-Reply-To: {}", root)?;
+                writeln!(out, "References: {}", root)?;
+            }
+            writeln!(out, "MIME-Version: 1.0")?;
+            writeln!(out, "Content-Type: text/plain; charset=utf-8")?;
+            writeln!(out, "Content-Transfer-Encoding: 8bit")?;
+            writeln!(out)?;
+
+            for line in sms.sms_text.lines() {
+                writeln!(out, "{}", escape_from(line))?;
+            }
+            if !sms.attachments.is_empty() {
+                writeln!(out)?;
+                for attachment in &sms.attachments {
+                    writeln!(
+                        out,
+                        "Attachment ({}): {}",
+                        attachment.content_type, attachment.content_url
+                    )?;
+                }
+            }
+            writeln!(out)?;
+        }
+    }
+}
+
+/// mboxrd quoting, so that no body line starts a new message.
+fn escape_from(line: &str) -> String {
+    if line.trim_start_matches('>').starts_with("From ") {
+        format!(">{}", line)
+    } else {
+        line.to_string()
+    }
+}
+
+fn address(number: &str) -> String {
+    format!("{}@{}", number, MAIL_DOMAIN)
+}
+
+fn message_id(did: &str, sms_id: &str) -> String {
+    format!("<{}.{}@{}>", sms_id, did, MAIL_DOMAIN)
+}
+
+fn utc(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn is_sent(sms: &AcrobitsSMS) -> bool {
+    sms.recipient.is_some()
+}
+
+fn contact(sms: &AcrobitsSMS) -> &str {
+    sms.recipient
+        .as_deref()
+        .or(sms.sender.as_deref())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acrobits::Attachment;
+    use crate::errors::VoipBitsError;
+    use crate::provider::Sent;
+    use crate::Opt;
+    use chrono::{Duration, TimeZone};
+
+    const DID: &str = "5145551234";
+
+    fn sms(
+        id: &str,
+        minutes: i64,
+        sender: Option<&str>,
+        recipient: Option<&str>,
+        text: &str,
+    ) -> AcrobitsSMS {
+        AcrobitsSMS {
+            sms_id: id.into(),
+            sending_date: Utc.timestamp_opt(1_650_000_000, 0).unwrap() + Duration::minutes(minutes),
+            sender: sender.map(Into::into),
+            recipient: recipient.map(Into::into),
+            sms_text: text.into(),
+            attachments: vec![],
+        }
+    }
+
+    fn export(format: Format, smss: &[AcrobitsSMS]) -> String {
+        let mut out = vec![];
+        write(format, DID, smss, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        let mut sent = sms("2", 1, None, Some("5145550000"), "ok, \"see\" you\nlater");
+        sent.attachments.push(Attachment {
+            content_type: "image/png".into(),
+            content_url: "https://example.com/a.png".into(),
+            content_size: None,
+            filename: None,
+        });
+        let smss = vec![sms("1", 0, Some("5145550000"), None, "hi"), sent];
+
+        assert_eq!(
+            export(Format::Csv, &smss),
+            "id,date,direction,did,contact,text,attachments\r\n\
+             1,2022-04-15T05:20:00Z,received,5145551234,5145550000,hi,\r\n\
+             2,2022-04-15T05:21:00Z,sent,5145551234,5145550000,\"ok, \"\"see\"\" you\nlater\",https://example.com/a.png\r\n"
+        );
+    }
+
+    #[test]
+    fn ndjson() {
+        let smss = vec![sms("1", 0, Some("5145550000"), None, "hi")];
+        let lines: Vec<serde_json::Value> = export(Format::Ndjson, &smss)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["date"], "2022-04-15T05:20:00Z");
+        assert_eq!(lines[0]["direction"], "received");
+        assert_eq!(lines[0]["contact"], "5145550000");
+    }
+
+    #[test]
+    fn mbox() {
+        let smss = vec![
+            sms("1", 0, Some("5145550000"), None, "hi"),
+            sms("2", 1, None, Some("5145559999"), "other thread"),
+            sms("3", 2, None, Some("5145550000"), "From here on\nbye"),
+        ];
+        let mbox = export(Format::Mbox, &smss);
+
+        assert_eq!(mbox.matches("\nFrom ").count() + 1, 3);
+        assert!(mbox.starts_with("From 5145550000@sms.voipbits.invalid Fri Apr 15 05:20:00 2022\n"));
+        assert!(mbox.contains("Date: Fri, 15 Apr 2022 05:22:00 +0000\n"));
+        assert!(mbox.contains(
+            "From: 5145551234@sms.voipbits.invalid\n\
+             To: 5145550000@sms.voipbits.invalid\n"
+        ));
+        assert!(mbox.contains("In-Reply-To: <1.5145551234@sms.voipbits.invalid>\n"));
+        assert!(mbox.contains("\n>From here on\nbye\n"));
+        // The other contact starts its own thread
+        assert_eq!(mbox.matches("In-Reply-To").count(), 1);
+    }
+
+    /// A carrier that does not know the DID of the credential
+    struct Rejecting;
+
+    #[async_trait::async_trait]
+    impl SmsProvider for Rejecting {
+        fn did(&self) -> &str {
+            DID
+        }
+
+        async fn send(&self, _: &str, _: &str, _: &[String]) -> Result<Sent, Error> {
+            unreachable!()
+        }
+
+        async fn fetch_after_id(&self, _: &str) -> Result<Vec<AcrobitsSMS>, Error> {
+            unreachable!()
+        }
+
+        async fn fetch_from_date(
+            &self,
+            _: Option<DateTime<Utc>>,
+        ) -> Result<Vec<AcrobitsSMS>, Error> {
+            Ok(vec![])
+        }
+
+        async fn verify(&self) -> Result<(), Error> {
+            Err(VoipBitsError::Upstream(format!("{} is not on this account", DID)).into())
+        }
+
+        async fn register_callback(&self, _: &Opt) -> Result<(), Error> {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_credential() {
+        let storage: Storage = std::sync::Arc::new(crate::storage::MemoryStore::new());
+        storage
+            .append_message(DID, &sms("1", 0, Some("5145550000"), None, "hi"))
+            .await
+            .unwrap();
+
+        let err = collect(&storage, &Rejecting, true).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VoipBitsError>(),
+            Some(VoipBitsError::Upstream(_))
+        ));
+    }
+}
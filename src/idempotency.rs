@@ -0,0 +1,104 @@
+//! The keys `/send` remembers its sends under, so that a softphone retrying a request
+//! that timed out does not text the contact twice.
+//!
+//! The softphone can name the send with an `Idempotency-Key` header or an
+//! `idempotency_key` parameter. Otherwise the same text to the same number within the
+//! same window of time is taken for a retry. A retry that falls in the next window
+//! sends again.
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+/// What a send is recognized by when the softphone gives no key.
+pub struct Request<'a> {
+    pub to: &'a str,
+    pub body: &'a str,
+    pub content_type: Option<&'a str>,
+}
+
+/// The key of a send from `did`, hashed so that neither the texts nor arbitrary client
+/// keys end up in the storage. `None` when the send cannot be told apart from a new one,
+/// without a key and with `window` at 0.
+pub fn key(
+    did: &str,
+    explicit: Option<&str>,
+    request: &Request,
+    now: DateTime<Utc>,
+    window: i64,
+) -> Option<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(did.as_bytes());
+
+    match explicit {
+        Some(key) => {
+            hasher.update(b"\0key\0");
+            hasher.update(key.as_bytes());
+        }
+        None if window > 0 => {
+            let bucket = now.timestamp().div_euclid(window);
+            for field in [
+                request.to,
+                request.body,
+                request.content_type.unwrap_or_default(),
+                &bucket.to_string(),
+            ] {
+                hasher.update(b"\0");
+                hasher.update(field.as_bytes());
+            }
+        }
+        None => return None,
+    }
+
+    Some(base64::encode_config(
+        hasher.finalize(),
+        base64::URL_SAFE_NO_PAD,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    const DID: &str = "5145551234";
+
+    const REQUEST: Request = Request {
+        to: "5145550000",
+        body: "on my way",
+        content_type: None,
+    };
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_650_000_000, 0).unwrap() + Duration::seconds(seconds)
+    }
+
+    #[test]
+    fn explicit() {
+        let first = key(DID, Some("abc"), &REQUEST, at(0), 60);
+        let other = Request {
+            body: "running late",
+            ..REQUEST
+        };
+
+        assert!(first.is_some());
+        assert_eq!(first, key(DID, Some("abc"), &other, at(9000), 0));
+        assert_ne!(first, key(DID, Some("abd"), &REQUEST, at(0), 60));
+        assert_ne!(first, key("5145559999", Some("abc"), &REQUEST, at(0), 60));
+    }
+
+    #[test]
+    fn derived() {
+        let first = key(DID, None, &REQUEST, at(0), 60);
+        let media = Request {
+            content_type: Some("application/x-acro-filetransfer+json"),
+            ..REQUEST
+        };
+
+        assert!(first.is_some());
+        assert_eq!(first, key(DID, None, &REQUEST, at(30), 60));
+        assert_ne!(first, key(DID, None, &REQUEST, at(60), 60));
+        assert_ne!(first, key(DID, Some("on my way"), &REQUEST, at(0), 60));
+        assert_ne!(first, key(DID, None, &media, at(0), 60));
+        assert_eq!(key(DID, None, &REQUEST, at(0), 0), None);
+    }
+}
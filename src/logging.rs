@@ -0,0 +1,209 @@
+//! Request/response logging that keeps credentials and message contents out of the logs.
+//!
+//! By default only the method, the path, the DID and the body sizes are logged, and the query
+//! parameters carrying secrets or message text are masked. Setting `LOG_SENSITIVE=true` logs
+//! everything verbatim, which is only meant for debugging.
+
+use axum::{
+    body::{Body, Bytes},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::{Method, Uri};
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info};
+
+static SENSITIVE: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static DID: RefCell<Option<String>>;
+}
+
+/// Query parameters that are masked unless sensitive logging is on.
+/// The query of a route not listed here is masked entirely.
+const SENSITIVE_PARAMS: &[(&str, &[&str])] = &[
+    ("/send", &["body"]),
+    ("/schedule", &["body"]),
+    ("/schedule/list", &[]),
+    ("/schedule/cancel", &[]),
+    ("/schedule/tick", &[]),
+    ("/notify", &["message", "sig"]),
+    ("/report", &["token"]),
+    ("/fetch", &[]),
+    ("/search", &["q", "contact"]),
+    ("/export", &[]),
+    ("/provision", &["cred"]),
+    ("/provision/***", &[]),
+    ("/setup", &[]),
+    ("/setup/qr", &["url"]),
+    ("/twilio/inbound", &["cred"]),
+    ("/telnyx/inbound", &[]),
+];
+
+const MASK: &str = "***";
+
+pub fn set_sensitive(sensitive: bool) {
+    SENSITIVE.store(sensitive, Ordering::Relaxed);
+}
+
+pub fn sensitive() -> bool {
+    SENSITIVE.load(Ordering::Relaxed)
+}
+
+/// Attaches the DID to the request log line. Called by the handlers once the credential is decrypted.
+pub fn record_did(did: &str) {
+    let _ = DID.try_with(|slot| *slot.borrow_mut() = Some(did.into()));
+}
+
+/// Displays the message text only if sensitive logging is on, otherwise only its length.
+pub fn text(text: &str) -> Redacted<'_> {
+    Redacted(text)
+}
+
+pub struct Redacted<'a>(&'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if sensitive() {
+            write!(f, "{:?}", self.0)
+        } else {
+            write!(f, "<{} chars>", self.0.chars().count())
+        }
+    }
+}
+
+pub async fn print_request_response(
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let (parts, body) = req.into_parts();
+    let bytes = buffer_and_print_request(&parts.method, &parts.uri, body).await?;
+    let req = Request::from_parts(parts, Body::from(bytes));
+
+    let (res, did) = DID
+        .scope(RefCell::new(None), async move {
+            let res = next.run(req).await;
+            (res, DID.with(|did| did.borrow_mut().take()))
+        })
+        .await;
+
+    let (parts, body) = res.into_parts();
+    let bytes = buffer_and_print_response(parts.status, did.as_deref(), body).await?;
+    let res = Response::from_parts(parts, Body::from(bytes));
+
+    Ok(res)
+}
+
+async fn buffer_and_print_request<B>(
+    method: &Method,
+    uri: &Uri,
+    body: B,
+) -> Result<Bytes, (StatusCode, String)>
+where
+    B: axum::body::HttpBody<Data = Bytes>,
+    B::Error: std::fmt::Display,
+{
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("[request] failed to read body: {}", err),
+            ));
+        }
+    };
+
+    if sensitive() {
+        if let Ok(body) = std::str::from_utf8(&bytes) {
+            debug!(
+                "[request] method={}, uri={:?}, body={:?}",
+                method, uri, body
+            );
+        }
+    } else {
+        let path = redact_path(uri.path());
+        info!(
+            "[request] method={}, path={}, query={}, body={} bytes",
+            method,
+            path,
+            redact_query(&path, uri.query().unwrap_or("")),
+            bytes.len()
+        );
+    }
+
+    Ok(bytes)
+}
+
+async fn buffer_and_print_response<B>(
+    status: StatusCode,
+    did: Option<&str>,
+    body: B,
+) -> Result<Bytes, (StatusCode, String)>
+where
+    B: axum::body::HttpBody<Data = Bytes>,
+    B::Error: std::fmt::Display,
+{
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("[response] failed to read body: {}", err),
+            ));
+        }
+    };
+
+    let did = did.unwrap_or("-");
+    if sensitive() {
+        if let Ok(body) = std::str::from_utf8(&bytes) {
+            debug!(
+                "[response] did = {}, code = {:?}, body = {:?}",
+                did, status, body
+            );
+        }
+    } else {
+        info!(
+            "[response] did = {}, code = {:?}, body = {} bytes",
+            did,
+            status,
+            bytes.len()
+        );
+    }
+
+    Ok(bytes)
+}
+
+/// `/provision/<token>` carries the encrypted credentials in the path.
+fn redact_path(path: &str) -> String {
+    match path.strip_prefix("/provision/") {
+        Some(token) if !token.is_empty() => format!("/provision/{}", MASK),
+        _ => path.into(),
+    }
+}
+
+fn redact_query(path: &str, query: &str) -> String {
+    if query.is_empty() {
+        return query.into();
+    }
+
+    let sensitive_params = match SENSITIVE_PARAMS.iter().find(|(route, _)| *route == path) {
+        Some((_, params)) => *params,
+        None => return MASK.into(),
+    };
+
+    query
+        .split('&')
+        .map(|pair| {
+            let key = pair.split('=').next().unwrap_or("");
+            if sensitive_params.contains(&key) {
+                format!("{}={}", key, MASK)
+            } else {
+                pair.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
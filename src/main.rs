@@ -1,37 +1,179 @@
 mod acrobits;
+mod archive;
+mod cli;
+mod credential;
 mod errors;
-mod push_manager;
+mod export;
+mod idempotency;
+mod logging;
+mod phone;
+mod provider;
+mod provisioning;
+mod reassembly;
+mod schedule;
+mod search;
+mod segment;
+mod signing;
+mod storage;
+mod telnyx;
+mod twilio;
 mod voipms;
 
-use crate::acrobits::Acrobits;
-use crate::push_manager::PushManager;
-use crate::voipms::VoipMS;
+use crate::acrobits::{Acrobits, FileTransfer, FILETRANSFER_CONTENT_TYPE};
+use crate::errors::VoipBitsError;
+use crate::provider::Sent;
+use crate::storage::{PushToken, ScheduledMessage, SendRecord, Storage, StorageBackend};
 use axum::{
-    body::{Body, Bytes},
-    extract::{Extension, Query},
-    http::{header, Request, StatusCode},
-    middleware::{self, Next},
-    response::{IntoResponse, Response},
+    body::Bytes,
+    extract::{
+        rejection::{FormRejection, QueryRejection},
+        Extension, Form, Path, Query,
+    },
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware,
+    response::{Html, IntoResponse},
     routing::{get, post},
     Json, Router,
 };
-use chrono::Utc;
-use hyper::{Method, Uri};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use fehler::{throw, throws};
 use lambda_web::{is_running_on_lambda, run_hyper_on_lambda, LambdaError};
+use qrcode::{render::svg, QrCode};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::net::SocketAddr;
 use structopt::StructOpt;
-use tracing::{debug, info, warn};
+use tracing::{info, warn};
 
-#[derive(Debug, Clone, StructOpt)]
+#[derive(Debug, StructOpt)]
 #[structopt(name = "voipbits", about = "This is VoipBits")]
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Runs the server, which is also what happens without any arguments
+    Serve(Opt),
+    /// Generates a server keypair
+    Keygen(cli::KeygenArgs),
+    /// Encrypts a credential into the blob the softphone sends
+    EncryptCred(cli::EncryptCredArgs),
+    /// Decrypts a credential blob, for debugging
+    DecryptCred(cli::DecryptCredArgs),
+    /// Exports the message history of the DID of a credential blob
+    Export(cli::ExportArgs),
+}
+
+#[derive(Debug, Clone, StructOpt)]
 pub struct Opt {
-    #[structopt(env)]
-    private_key: String,
+    #[structopt(flatten)]
+    keys: credential::KeyRing,
 
     #[structopt(env, default_value = "https://voipbits.wooya.me")]
     server_url: String,
+
+    /// Where the push tokens live: dynamodb, sqlite or memory
+    #[structopt(env, default_value = "dynamodb")]
+    storage: StorageBackend,
+
+    #[structopt(env, default_value = "voipbits-push-tokens")]
+    dynamodb_table: String,
+
+    /// Message log of the carriers without a message history API
+    #[structopt(env, default_value = "voipbits-messages")]
+    dynamodb_messages_table: String,
+
+    /// Messages waiting to be sent later
+    #[structopt(env, default_value = "voipbits-scheduled")]
+    dynamodb_schedule_table: String,
+
+    #[structopt(env, default_value = "voipbits.sqlite")]
+    sqlite_path: String,
+
+    /// Server key for signing the voip.ms and Twilio SMS callback URLs of each DID.
+    /// Callbacks are not authenticated if it is not set.
+    #[structopt(env)]
+    notify_secret: Option<String>,
+
+    /// Keep accepting unsigned callbacks until this time (RFC 3339),
+    /// so that DIDs provisioned before signing was enabled keep working.
+    #[structopt(env)]
+    notify_unsigned_until: Option<DateTime<Utc>>,
+
+    /// Log credentials and message texts verbatim. Only turn it on for debugging.
+    #[structopt(env, parse(try_from_str), default_value = "false")]
+    log_sensitive: bool,
+
+    /// Keep every message in the storage, so that full syncs go back further than
+    /// the carrier does
+    #[structopt(env, parse(try_from_str), default_value = "false")]
+    archive_messages: bool,
+
+    /// ISO country code of the destination numbers typed without a country code
+    #[structopt(env, default_value = "US")]
+    default_country: phone::Country,
+
+    /// Whether the voip.ms accounts can send SMS outside North America
+    #[structopt(env, parse(try_from_str), default_value = "false")]
+    voipms_international_sms: bool,
+
+    /// Add `(i/n)` to the parts of texts too long for a single SMS, for the carriers
+    /// that send them as separate messages
+    #[structopt(env, parse(try_from_str), default_value = "false")]
+    segment_markers: bool,
+
+    /// Seconds between the parts of a long inbound text for `/fetch` to join them
+    /// into one message, 0 to turn it off
+    #[structopt(env, default_value = "0")]
+    reassemble_window: i64,
+
+    /// Seconds after a push during which the messages from the same sender are not
+    /// pushed again, 0 to push every message
+    #[structopt(env, default_value = "0")]
+    notify_debounce: i64,
+
+    /// Seconds during which `/send` takes the same text to the same number for a retry
+    /// when the softphone gives no idempotency key, 0 to only go by the key
+    #[structopt(env, default_value = "60")]
+    send_dedup_window: i64,
+
+    /// The timezone of the voip.ms accounts (Main Menu → Account Settings → Default Timezone),
+    /// which the voip.ms API gives the message dates in
+    #[structopt(env, default_value = "America/New_York")]
+    voipms_timezone: Tz,
+
+    /// The timezone of the times in `@at` and `/schedule`
+    #[structopt(env, default_value = "America/New_York")]
+    schedule_timezone: Tz,
+
+    /// Seconds between the checks for due scheduled messages of the local server
+    #[structopt(env, default_value = "30")]
+    schedule_interval: u64,
+
+    /// The voip.ms REST API endpoint, only worth changing for testing
+    #[structopt(env, default_value = voipms::VOIPMS_API_URL)]
+    voipms_api_url: String,
+
+    /// Base URL of the Twilio REST API, only worth changing for testing
+    #[structopt(env, default_value = twilio::TWILIO_API_URL)]
+    twilio_api_url: String,
+
+    /// Public key of the Telnyx account (base64), which signs its webhooks.
+    /// Telnyx webhooks are rejected if it is not set.
+    #[structopt(env)]
+    telnyx_public_key: Option<String>,
+
+    /// Base URL of the Telnyx API, only worth changing for testing
+    #[structopt(env, default_value = telnyx::TELNYX_API_URL)]
+    telnyx_api_url: String,
+
+    /// The server public key the setup page encrypts with (base64 of the DER
+    /// SubjectPublicKeyInfo). Derived from the private key if not set.
+    #[structopt(env)]
+    public_key: Option<String>,
+
+    /// JSON file of extra Acrobits settings for the provisioned accounts,
+    /// see `provisioning::Overrides`
+    #[structopt(env, parse(try_from_str = provisioning::Overrides::load))]
+    provisioning_config: Option<provisioning::Overrides>,
 }
 
 impl Opt {
@@ -51,21 +193,78 @@ impl Opt {
 
     pub fn send_url(&self) -> String {
         format!(
-            "{url}/send?to=%sms_to%&body=%sms_body%",
+            "{url}/send?to=%sms_to%&body=%sms_body%&content_type=%sms_content_type%",
             url = self.server_url
         )
     }
 
-    #[allow(unused)]
     pub fn provision_url(&self) -> String {
         format!("{url}/provision", url = self.server_url)
     }
 
-    pub fn notify_url(&self) -> String {
-        format!(
-            "{url}/notify?message={{MESSAGE}}&from={{FROM}}&to={{TO}}",
+    pub fn notify_url(&self, did: &str) -> String {
+        let url = format!(
+            "{url}/notify?message={{MESSAGE}}&from={{FROM}}&to={{TO}}&id={{ID}}",
             url = self.server_url
-        )
+        );
+
+        match self.notify_secret() {
+            Some(secret) => format!("{}&sig={}", url, signing::sign_did(secret, did)),
+            None => url,
+        }
+    }
+
+    /// Twilio inbound webhook of a DID. The encrypted credential is part of it,
+    /// since the auth token is needed to check the request signature. So is the
+    /// signature of the DID like in `notify_url`, as anyone can encrypt a credential
+    /// that pairs their own Twilio account with someone else's DID.
+    pub fn twilio_inbound_url(&self, did: &str, cred: &str) -> String {
+        let url = format!(
+            "{url}/twilio/inbound?cred={cred}",
+            url = self.server_url,
+            cred = urlencoding::encode(cred)
+        );
+
+        match self.notify_secret() {
+            Some(secret) => format!("{}&sig={}", url, signing::sign_did(secret, did)),
+            None => url,
+        }
+    }
+
+    pub fn telnyx_inbound_url(&self) -> String {
+        format!("{url}/telnyx/inbound", url = self.server_url)
+    }
+
+    pub fn public_key(&self) -> String {
+        match self.public_key.as_deref().filter(|key| !key.is_empty()) {
+            Some(key) => key.into(),
+            None => self.keys.public_key(),
+        }
+    }
+
+    fn notify_secret(&self) -> Option<&str> {
+        self.notify_secret.as_deref().filter(|s| !s.is_empty())
+    }
+
+    /// Checks that a voip.ms or Twilio callback for `did` comes with the signature we
+    /// registered.
+    #[throws(VoipBitsError)]
+    pub fn verify_notify(&self, did: &str, sig: Option<&str>) {
+        let secret = match self.notify_secret() {
+            Some(secret) => secret,
+            None => return,
+        };
+
+        match sig {
+            Some(sig) if signing::verify_did(secret, did, sig) => {}
+            None if matches!(self.notify_unsigned_until, Some(until) if Utc::now() < until) => {
+                warn!(
+                    "[notify] Accepting unsigned callback for {} during the grace period",
+                    did
+                );
+            }
+            _ => throw!(VoipBitsError::InvalidSignature(did.into())),
+        }
     }
 }
 
@@ -73,17 +272,55 @@ impl Opt {
 async fn main() -> Result<(), LambdaError> {
     tracing_subscriber::fmt::init();
 
-    let opt = Opt::from_args();
+    // Lambda starts the binary without arguments
+    let mut args: Vec<_> = std::env::args_os().collect();
+    if args.len() == 1 {
+        args.push("serve".into());
+    }
+
+    match Command::from_iter(args) {
+        Command::Serve(opt) => serve(opt).await?,
+        Command::Keygen(args) => cli::keygen(args)?,
+        Command::EncryptCred(args) => cli::encrypt_cred(args)?,
+        Command::DecryptCred(args) => cli::decrypt_cred(args)?,
+        Command::Export(args) => cli::export(args).await?,
+    }
+    Ok(())
+}
+
+async fn serve(opt: Opt) -> Result<(), LambdaError> {
+    logging::set_sensitive(opt.log_sensitive);
+    if opt.log_sensitive {
+        warn!("Sensitive logging is on, credentials and messages will end up in the logs");
+    }
+    let storage = storage::connect(&opt).await?;
+
+    if !is_running_on_lambda() {
+        // Lambda invokes `/schedule/tick` instead, see serverless.yml
+        tokio::spawn(schedule::run(opt.clone(), storage.clone()));
+    }
 
     // build our application with a route
     let app = Router::new()
         .route("/send", post(send))
+        .route("/schedule", post(schedule_create))
+        .route("/schedule/list", post(schedule_list))
+        .route("/schedule/cancel", post(schedule_cancel))
+        .route("/schedule/tick", post(schedule_tick))
         .route("/notify", get(notify))
-        .route("/provision", post(provision))
+        .route("/provision", post(provision).get(provision_by_url))
+        .route("/provision/:token", get(provision_by_token))
+        .route("/setup", get(setup))
+        .route("/setup/qr", get(setup_qr))
         .route("/fetch", post(fetch))
+        .route("/search", post(search))
+        .route("/export", post(export))
         .route("/report", post(report))
-        .layer(middleware::from_fn(print_request_response))
-        .layer(Extension(opt));
+        .route("/twilio/inbound", post(twilio_inbound))
+        .route("/telnyx/inbound", post(telnyx_inbound))
+        .layer(middleware::from_fn(logging::print_request_response))
+        .layer(Extension(opt))
+        .layer(Extension(storage));
 
     if is_running_on_lambda() {
         // Run app on AWS Lambda
@@ -98,143 +335,358 @@ async fn main() -> Result<(), LambdaError> {
     Ok(())
 }
 
-async fn print_request_response(
-    req: Request<Body>,
-    next: Next<Body>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let (parts, body) = req.into_parts();
-    let bytes = buffer_and_print_request(&parts.method, &parts.uri, body).await?;
-    let req = Request::from_parts(parts, Body::from(bytes));
-
-    let res = next.run(req).await;
-
-    let (parts, body) = res.into_parts();
-    let bytes = buffer_and_print_response(parts.status, body).await?;
-    let res = Response::from_parts(parts, Body::from(bytes));
-
-    Ok(res)
-}
-
-async fn buffer_and_print_request<B>(
-    method: &Method,
-    uri: &Uri,
-    body: B,
-) -> Result<Bytes, (StatusCode, String)>
-where
-    B: axum::body::HttpBody<Data = Bytes>,
-    B::Error: std::fmt::Display,
-{
-    let bytes = match hyper::body::to_bytes(body).await {
-        Ok(bytes) => bytes,
-        Err(err) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!("[request] failed to read body: {}", err),
-            ));
-        }
+/* -------------------------------- Handlers -------------------------------- */
+#[derive(Deserialize, Debug)]
+struct SendQuery {
+    to: String,
+    body: String,
+    /// `FILETRANSFER_CONTENT_TYPE` when the body carries attachments
+    content_type: Option<String>,
+    /// Names the send for retries, like the `Idempotency-Key` header
+    idempotency_key: Option<String>,
+}
+
+/// After this many seconds, a send still pending under an idempotency key is presumed
+/// dead and a retry sends again.
+const SEND_CLAIM_TIMEOUT: i64 = 120;
+
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn send(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<SendQuery>, QueryRejection>,
+    headers: HeaderMap,
+    cred: String,
+) -> Json<Value> {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let to = &query.to;
+    let body = &query.body;
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    let did = provider.did();
+    logging::record_did(did);
+
+    let (text, media) = if query.content_type.as_deref() == Some(FILETRANSFER_CONTENT_TYPE) {
+        let transfer: FileTransfer = serde_json::from_str(body)
+            .map_err(|e| VoipBitsError::Validation(format!("invalid attachments: {}", e)))?;
+        let media: Vec<_> = transfer
+            .attachments
+            .into_iter()
+            .map(|attachment| attachment.content_url)
+            .collect();
+
+        (transfer.body.unwrap_or_default(), media)
+    } else {
+        (body.clone(), vec![])
     };
 
-    if let Ok(body) = std::str::from_utf8(&bytes) {
-        debug!(
-            "[request] method={}, uri={:?}, body={:?}",
-            method, uri, body
-        );
+    let now = Utc::now();
+    let scheduled = schedule::parse_prefix(&text, now, opt.schedule_timezone)?;
+
+    let explicit_key = headers
+        .get("idempotency-key")
+        .and_then(|key| key.to_str().ok())
+        .or(query.idempotency_key.as_deref());
+    let request = idempotency::Request {
+        to,
+        body,
+        content_type: query.content_type.as_deref(),
+    };
+    let key = idempotency::key(did, explicit_key, &request, now, opt.send_dedup_window);
+
+    if let Some(ref key) = key {
+        let stale = now - Duration::seconds(SEND_CLAIM_TIMEOUT);
+        match storage.claim_send(did, key, now, stale).await? {
+            Some(SendRecord::Sent(sent)) => {
+                info!("[send] Already sent ({} -> {}) as {:?}", did, to, sent.ids);
+                return send_response(&sent)?;
+            }
+            Some(SendRecord::Pending) => throw!(VoipBitsError::SendInProgress(key.clone())),
+            None => {}
+        }
     }
 
-    Ok(bytes)
-}
-
-async fn buffer_and_print_response<B>(
-    status: StatusCode,
-    body: B,
-) -> Result<Bytes, (StatusCode, String)>
-where
-    B: axum::body::HttpBody<Data = Bytes>,
-    B::Error: std::fmt::Display,
-{
-    let bytes = match hyper::body::to_bytes(body).await {
-        Ok(bytes) => bytes,
-        Err(err) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!("[response] failed to read body: {}", err),
-            ));
+    let sent = match scheduled {
+        Some((send_at, text)) => {
+            let message = ScheduledMessage::new(did, &cred, to, text, &media, send_at);
+            queue_message(&storage, &message).await.map(|()| Sent {
+                ids: vec![message.id],
+                segments: 0,
+            })
+        }
+        None => {
+            info!(
+                "[send] Sending message ({} -> {}) {}",
+                did,
+                to,
+                logging::text(body)
+            );
+            provider
+                .send(to, &text, &media)
+                .await
+                .map_err(VoipBitsError::from)
         }
     };
 
-    if let Ok(body) = std::str::from_utf8(&bytes) {
-        debug!("[response] code = {:?}, body = {:?}", status, body);
-    }
+    let sent = match (sent, key) {
+        (Ok(sent), key) => {
+            if let Some(key) = key {
+                storage.complete_send(did, &key, &sent).await?;
+            }
+            // Texts sent as several SMS are archived part by part as `/fetch` sees them
+            if let (true, [id], None) = (opt.archive_messages, sent.ids.as_slice(), scheduled) {
+                archive::record(&storage, did, &[archive::sent(id, to, &text, &media)]).await;
+            }
+            sent
+        }
+        (Err(e), Some(key)) => {
+            storage.release_send(did, &key).await?;
+            throw!(e)
+        }
+        (Err(e), None) => throw!(e),
+    };
 
-    Ok(bytes)
+    send_response(&sent)?
+}
+
+#[throws(VoipBitsError)]
+fn send_response(sent: &Sent) -> Json<Value> {
+    let sms_id = sent
+        .ids
+        .first()
+        .ok_or_else(|| VoipBitsError::Internal("the carrier gave no message id".into()))?;
+
+    Json(json!({
+        "sms_id": sms_id,
+        "segments": sent.segments,
+    }))
+}
+
+#[throws(VoipBitsError)]
+async fn queue_message(storage: &Storage, message: &ScheduledMessage) {
+    info!(
+        "[schedule] Queueing message {} ({} -> {}) for {}: {}",
+        message.id,
+        message.did,
+        message.to,
+        message.send_at,
+        logging::text(&message.text)
+    );
+    storage.schedule_message(message).await?;
 }
 
-/* -------------------------------- Handlers -------------------------------- */
 #[derive(Deserialize, Debug)]
-struct SendQuery {
+struct ScheduleQuery {
     to: String,
     body: String,
+    /// RFC 3339, or a wall clock time of `SCHEDULE_TIMEZONE`, see `schedule::parse_time`
+    at: String,
 }
 
-#[tracing::instrument(skip(opt))]
-async fn send(
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn schedule_create(
     Extension(opt): Extension<Opt>,
-    query: Query<SendQuery>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<ScheduleQuery>, QueryRejection>,
     cred: String,
 ) -> Json<Value> {
-    let to = &query.to;
-    let body = &query.body;
-    let voipms = VoipMS::from_cred(&opt.private_key, &cred).unwrap();
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    let did = provider.did();
+    logging::record_did(did);
 
-    info!(
-        "[send] Sending message ({} -> {}) '{}'",
-        voipms.did, to, body
-    );
-    let ret_ids = voipms.send_sms(to, body).await.unwrap();
+    let send_at = schedule::parse_time(&query.at, Utc::now(), opt.schedule_timezone)?;
+    if query.body.trim().is_empty() {
+        throw!(VoipBitsError::EmptyMessage);
+    }
+
+    let message = ScheduledMessage::new(did, &cred, &query.to, &query.body, &[], send_at);
+    queue_message(&storage, &message).await?;
 
     Json(json!({
-        "sms_id": ret_ids[0]
+        "id": message.id,
+        "send_at": message.send_at,
     }))
 }
 
-#[tracing::instrument(skip(opt))]
-async fn provision(Extension(opt): Extension<Opt>, cred: String) -> impl IntoResponse {
-    // cred is in <did>:<account>:<password> form
-
-    let voipms = VoipMS::from_cred(&opt.private_key, &cred).unwrap();
-
-    voipms.set_sms_callback(&opt).await.unwrap();
-    info!("Provisioning for {}", voipms.did);
-
-    let xml = format!(
-        "<account>
-            <pushTokenReporterUrl>{}</pushTokenReporterUrl>
-            <pushTokenReporterPostData>{cred}</pushTokenReporterPostData>
-            <pushTokenReporterContentType>text/plain</pushTokenReporterContentType>
-
-            <genericSmsFetchUrl>{}</genericSmsFetchUrl>
-            <genericSmsFetchPostData>{cred}</genericSmsFetchPostData>
-            <genericSmsFetchContentType>text/plain</genericSmsFetchContentType>
-
-            <genericSmsSendUrl>{}</genericSmsSendUrl>
-            <genericSmsPostData>{cred}</genericSmsPostData>
-            <genericSmsContentType>text/plain</genericSmsContentType>
-            
-            <voipmsNotificationUrl>{}</voipmsNotificationUrl>
-            <allowMessage>1</allowMessage>
-            <voiceMailNumber>*97</voiceMailNumber>
-        </account>",
-        opt.report_url().replace("&", "&amp;"),
-        opt.fetch_url().replace("&", "&amp;"),
-        opt.send_url().replace("&", "&amp;"),
-        opt.notify_url().replace("&", "&amp;"),
-        cred = cred,
-    );
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn schedule_list(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    cred: String,
+) -> Json<Value> {
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    logging::record_did(provider.did());
+    // The messages were queued with a credential the carrier accepted, not this one
+    provider.verify().await?;
+
+    let scheduled: Vec<_> = storage
+        .list_scheduled(provider.did())
+        .await?
+        .into_iter()
+        .map(|message| {
+            json!({
+                "id": message.id,
+                "to": message.to,
+                "text": message.text,
+                "media": message.media,
+                "send_at": message.send_at,
+            })
+        })
+        .collect();
+
+    Json(json!({ "scheduled": scheduled }))
+}
+
+#[derive(Deserialize, Debug)]
+struct ScheduleCancelQuery {
+    id: String,
+}
+
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn schedule_cancel(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<ScheduleCancelQuery>, QueryRejection>,
+    cred: String,
+) -> Json<Value> {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    logging::record_did(provider.did());
+    provider.verify().await?;
+
+    let cancelled = storage.take_scheduled(provider.did(), &query.id).await?;
+    info!("[schedule] Cancelling {}: {}", query.id, cancelled);
+
+    Json(json!({ "cancelled": cancelled }))
+}
+
+/// Sends the due messages, for the periodic invocation on Lambda.
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn schedule_tick(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+) -> Json<Value> {
+    let sent = schedule::deliver_due(&opt, &storage, Utc::now()).await?;
+
+    Json(json!({ "sent": sent }))
+}
+
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn provision(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    cred: String,
+) -> impl IntoResponse {
+    // cred is the encrypted credential, see `credential::Credential`
+    provision_account(&opt, &storage, &cred, None).await?
+}
+
+#[derive(Deserialize, Debug)]
+struct ProvisionQuery {
+    cred: String,
+}
+
+/// The provisioning URL of the setup page, for "Add account from QR/URL".
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn provision_by_url(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<ProvisionQuery>, QueryRejection>,
+) -> impl IntoResponse {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    provision_account(&opt, &storage, &query.cred, None).await?
+}
+
+#[derive(Deserialize, Debug)]
+struct ProvisionTokenQuery {
+    /// The server to register to, e.g. `montreal2` for voip.ms
+    pop: Option<String>,
+}
+
+/// External provisioning from a URL, with the credential in the path (see `credential::to_token`).
+/// Sets up the SIP account of the DID along with SMS, so that a single scan is enough.
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn provision_by_token(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    Path(token): Path<String>,
+    query: Result<Query<ProvisionTokenQuery>, QueryRejection>,
+) -> impl IntoResponse {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let cred = credential::from_token(&token)?;
+    let pop = query.pop.as_deref().filter(|pop| !pop.is_empty());
+
+    provision_account(&opt, &storage, &cred, Some(pop)).await?
+}
+
+/// `sip` is `Some(pop)` to provision the SIP account as well.
+#[throws(VoipBitsError)]
+async fn provision_account(
+    opt: &Opt,
+    storage: &Storage,
+    cred: &str,
+    sip: Option<Option<&str>>,
+) -> (StatusCode, [(header::HeaderName, &'static str); 1], String) {
+    let provider = provider::from_cred(opt, storage, cred)?;
+    logging::record_did(provider.did());
+
+    provider.register_callback(opt).await?;
+    info!("Provisioning for {}", provider.did());
+
+    let sip = match sip {
+        Some(pop) => provider.sip_account(pop).await?,
+        None => None,
+    };
+    let account = provisioning::Account::new(opt, provider.did(), cred, sip);
 
     (
         StatusCode::OK,
         [(header::CONTENT_TYPE, "application/xml")],
-        xml,
+        account.to_xml(opt.provisioning_config.as_ref())?,
+    )
+}
+
+/// The self-service page that encrypts the credentials in the browser.
+#[tracing::instrument(skip_all)]
+async fn setup(Extension(opt): Extension<Opt>) -> Html<String> {
+    let config = json!({
+        "publicKey": opt.public_key(),
+        "keyId": opt.keys.key_id(),
+        "provisionUrl": opt.provision_url(),
+    });
+    // Nothing in the config can close the script tag
+    let config = config.to_string().replace("</", "<\\/");
+
+    Html(include_str!("setup.html").replace("{{CONFIG}}", &config))
+}
+
+#[derive(Deserialize, Debug)]
+struct SetupQrQuery {
+    url: String,
+}
+
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn setup_qr(query: Result<Query<SetupQrQuery>, QueryRejection>) -> impl IntoResponse {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let svg = QrCode::new(query.url.as_bytes())
+        .map_err(|e| VoipBitsError::Validation(format!("cannot make a QR code: {}", e)))?
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .build();
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "image/svg+xml")],
+        svg,
     )
 }
 
@@ -245,25 +697,34 @@ struct ReportQuery {
     selector: String,
 }
 
-#[tracing::instrument(skip(opt))]
-async fn report(Extension(opt): Extension<Opt>, query: Query<ReportQuery>, cred: String) {
-    // cred is in <did>:<account>:<password> form
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn report(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<ReportQuery>, QueryRejection>,
+    headers: HeaderMap,
+    cred: String,
+) {
+    // cred is the encrypted credential, see `credential::Credential`
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
 
     let push_token = &query.token;
     let appid = &query.appid;
     let selector = &query.selector;
-    if push_token.trim().len() == 0 {
+    if push_token.trim().is_empty() {
         // Sometimes acrobits gives you empty push token, we just ignore it.
         return;
     }
-    let voipms = VoipMS::from_cred(&opt.private_key, &cred).unwrap();
-    info!("[report] New report for {}", voipms.did);
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    logging::record_did(provider.did());
+    info!("[report] New report for {}", provider.did());
 
-    PushManager::new()
-        .await
-        .save_token(&voipms.did, appid, push_token, selector)
-        .await
-        .unwrap();
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok());
+    let token = PushToken::new(appid, push_token, selector, user_agent);
+    storage.save_token(provider.did(), &token).await?;
 }
 
 #[derive(Deserialize, Debug)]
@@ -271,37 +732,175 @@ struct NotifyQuery {
     message: String,
     from: String,
     to: String,
+    /// The voip.ms id of the message, missing from the callback URLs of older versions
+    id: Option<String>,
+    sig: Option<String>,
 }
 
-#[tracing::instrument]
-async fn notify(query: Query<NotifyQuery>) -> &'static str {
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn notify(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<NotifyQuery>, QueryRejection>,
+) -> &'static str {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
     let message = &query.message;
     let did = &query.to;
     let from = &query.from;
 
-    info!("New message {} -> {}: '{}'", from, did, message);
+    logging::record_did(did);
+    opt.verify_notify(did, query.sig.as_deref())?;
 
-    let acrobits = Acrobits::new();
+    info!(
+        "New message {} -> {}: {}",
+        from,
+        did,
+        logging::text(message)
+    );
 
-    let pm = PushManager::new().await;
+    if let (true, Some(id)) = (opt.archive_messages, &query.id) {
+        archive::record(&storage, did, &[archive::received(id, from, message)]).await;
+    }
+    push_message(&opt, &storage, did, from, message).await?;
 
-    let tokens = pm.get_tokens(did).await.unwrap();
+    "ok"
+}
 
-    let mut failed_tokens = vec![];
-    for (appid, push_token, selector) in tokens {
-        if let Err(e) = acrobits
-            .notify(&appid, &push_token, &selector, from, message)
-            .await
-        {
-            warn!(
-                "Notify device error: {:?}, removing the push token {}",
-                e, push_token
+/// Pushes an inbound message to the devices of the DID, once for a burst of messages
+/// from the same sender such as the parts of a long text.
+#[throws(VoipBitsError)]
+async fn push_message(opt: &Opt, storage: &Storage, did: &str, from: &str, message: &str) {
+    if opt.notify_debounce > 0 {
+        let now = Utc::now();
+        let since = now - Duration::seconds(opt.notify_debounce);
+        if !storage.claim_notification(did, from, now, since).await? {
+            info!(
+                "[notify] Already pushed a message from {} in the last {}s",
+                from, opt.notify_debounce
             );
-            failed_tokens.push((appid, push_token, selector));
+            return;
         }
     }
 
-    pm.remove_tokens(did, &failed_tokens).await.unwrap();
+    Acrobits::new()
+        .notify_devices(storage, did, from, message)
+        .await?;
+}
+
+#[derive(Deserialize, Debug)]
+struct TwilioInboundQuery {
+    cred: String,
+    sig: Option<String>,
+}
+
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn twilio_inbound(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<TwilioInboundQuery>, QueryRejection>,
+    uri: Uri,
+    headers: HeaderMap,
+    form: Result<Form<Vec<(String, String)>>, FormRejection>,
+) -> impl IntoResponse {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let Form(params) = form.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let cred = credential::Credential::decrypt(&opt.keys, &query.cred)?;
+    logging::record_did(&cred.did);
+    opt.verify_notify(&cred.did, query.sig.as_deref())?;
+
+    // Twilio signs the URL it was configured with, which is ours plus the path and query
+    let url = format!(
+        "{}{}",
+        opt.server_url,
+        uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("")
+    );
+    let signature = headers
+        .get("x-twilio-signature")
+        .and_then(|sig| sig.to_str().ok())
+        .unwrap_or("");
+    if !twilio::verify_signature(&cred.secret, &url, &params, signature) {
+        throw!(VoipBitsError::InvalidSignature(cred.did));
+    }
+
+    let (did, sms) = twilio::inbound_message(&params)?;
+    if did != cred.did {
+        throw!(VoipBitsError::InvalidSignature(did));
+    }
+    let from = sms.sender.as_deref().unwrap_or_default();
+
+    info!(
+        "New message {} -> {}: {}",
+        from,
+        did,
+        logging::text(&sms.sms_text)
+    );
+
+    let message = if sms.attachments.is_empty() {
+        sms.sms_text.clone()
+    } else {
+        format!("{} [{} attachment(s)]", sms.sms_text, sms.attachments.len())
+    };
+    push_message(&opt, &storage, &did, from, message.trim()).await?;
+
+    // Empty TwiML, we don't reply to the sender
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/xml")],
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Response></Response>",
+    )
+}
+
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn telnyx_inbound(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> &'static str {
+    let public_key = opt
+        .telnyx_public_key
+        .as_deref()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| VoipBitsError::InvalidSignature("telnyx webhook".into()))?;
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+    };
+    telnyx::verify_webhook(
+        public_key,
+        header("telnyx-signature-ed25519"),
+        header("telnyx-timestamp"),
+        &body,
+        Utc::now(),
+    )?;
+
+    let (did, sms) = match telnyx::inbound_message(&body)? {
+        Some(inbound) => inbound,
+        // Delivery reports and the like
+        None => return "ok",
+    };
+    logging::record_did(&did);
+    let from = sms.sender.as_deref().unwrap_or_default();
+
+    info!(
+        "New message {} -> {}: {}",
+        from,
+        did,
+        logging::text(&sms.sms_text)
+    );
+    storage.append_message(&did, &sms).await?;
+
+    let message = if sms.attachments.is_empty() {
+        sms.sms_text.clone()
+    } else {
+        format!("{} [{} attachment(s)]", sms.sms_text, sms.attachments.len())
+    };
+    push_message(&opt, &storage, &did, from, message.trim()).await?;
 
     "ok"
 }
@@ -311,23 +910,46 @@ struct FetchQuery {
     last_id: Option<String>,
 }
 
-#[tracing::instrument(skip(opt))]
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
 async fn fetch(
     Extension(opt): Extension<Opt>,
-    query: Query<FetchQuery>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<FetchQuery>, QueryRejection>,
     cred: String,
 ) -> Json<Value> {
-    let voipms = VoipMS::from_cred(&opt.private_key, &cred).unwrap();
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    logging::record_did(provider.did());
 
+    let did = provider.did();
     let payload = match query.last_id {
         Some(ref last_id) => {
+            let mut smss = provider.fetch_after_id(last_id).await?;
+            if opt.archive_messages {
+                archive::record(&storage, did, &smss).await;
+            }
+
             // Fetching last ID, which means acrobits already have the messages sent by us.
             // So we only return the incoming messages
-            let mut smss = voipms.fetch_sms_after_id(last_id).await.unwrap();
-            smss.retain(|sms| sms.recipient.is_none() && &sms.sms_id > last_id);
+            smss.retain(|sms| sms.recipient.is_none());
             smss
         }
-        None => voipms.fetch_sms_from_date(None).await.unwrap(),
+        None if opt.archive_messages => {
+            let live = provider.fetch_from_date(None).await?;
+            let archived = storage.list_messages(did, archive::beginning()).await?;
+            archive::record(&storage, did, &archive::missing(&live, &archived)).await;
+
+            let smss = archive::merge(live, archived);
+            info!("[fetch] {} SMS with the archive", smss.len());
+            smss
+        }
+        None => provider.fetch_from_date(None).await?,
+    };
+    let payload = if opt.reassemble_window > 0 {
+        reassembly::reassemble(payload, Duration::seconds(opt.reassemble_window))
+    } else {
+        payload
     };
     info!("[fetch] Total {} SMS", payload.len());
 
@@ -342,3 +964,210 @@ async fn fetch(
 
     Json(body)
 }
+
+#[derive(Deserialize, Debug)]
+struct SearchQuery {
+    /// Words the messages all have
+    q: Option<String>,
+    /// The number the messages were sent to or received from
+    contact: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+/// Searches the message log of the DID, newest first.
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn search(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<SearchQuery>, QueryRejection>,
+    cred: String,
+) -> Json<Value> {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    let did = provider.did();
+    logging::record_did(did);
+    // Anyone can encrypt a credential for any DID, the carrier tells whether it is good
+    provider.verify().await?;
+
+    let since = query.since.unwrap_or_else(archive::beginning);
+    let until = query.until.unwrap_or_else(Utc::now);
+    let words = search::words(query.q.as_deref().unwrap_or_default());
+    let contact = query.contact.as_deref();
+    let limit = query
+        .limit
+        .unwrap_or(search::DEFAULT_LIMIT)
+        .min(search::MAX_LIMIT);
+
+    let smss = if words.is_empty() {
+        let mut smss = storage.list_messages(did, since).await?;
+        smss.retain(|sms| sms.sending_date <= until);
+        if let Some(contact) = contact {
+            smss.retain(|sms| search::with_contact(sms, contact));
+        }
+        smss.reverse();
+        smss.truncate(limit);
+        smss
+    } else {
+        storage
+            .search_messages(did, &words, since, until, contact, limit)
+            .await?
+    };
+    info!("[search] {} SMS", smss.len());
+
+    Json(json!({ "smss": smss }))
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportQuery {
+    format: Option<export::Format>,
+}
+
+/// The message history of the DID as a file, CSV unless another `format` is asked for.
+#[throws(VoipBitsError)]
+#[tracing::instrument(skip_all)]
+async fn export(
+    Extension(opt): Extension<Opt>,
+    Extension(storage): Extension<Storage>,
+    query: Result<Query<ExportQuery>, QueryRejection>,
+    cred: String,
+) -> impl IntoResponse {
+    let query = query.map_err(|e| VoipBitsError::Validation(e.to_string()))?;
+    let provider = provider::from_cred(&opt, &storage, &cred)?;
+    let did = provider.did();
+    logging::record_did(did);
+
+    let format = query.format.unwrap_or(export::Format::Csv);
+    let smss = export::collect(&storage, provider.as_ref(), opt.archive_messages).await?;
+    info!("[export] {} SMS as {:?}", smss.len(), format);
+
+    let mut body = vec![];
+    export::write(format, did, &smss, &mut body)?;
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"voipbits-{}.{}\"",
+                    did,
+                    format.extension()
+                ),
+            ),
+        ],
+        body,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential::{Credential, ProviderKind};
+    use hmac::{Hmac, Mac, NewMac};
+    use sha1::Sha1;
+
+    /// The DID of someone else than the Twilio account holder
+    const VICTIM: &str = "15145551111";
+
+    /// A server with a fresh key that signs the callback URLs
+    fn opt() -> Opt {
+        let (private_key, _) = credential::generate_keypair(1024).unwrap();
+        let mut opt = Opt::from_iter_safe(vec!["voipbits", &private_key]).unwrap();
+        opt.server_url = "https://voipbits.example.com".into();
+        opt.notify_secret = Some("notify secret".into());
+        opt.notify_unsigned_until = None;
+        opt.notify_debounce = 0;
+        opt
+    }
+
+    /// A credential for `did` with a Twilio account of one's own
+    fn minted(opt: &Opt, did: &str) -> String {
+        let cred = Credential {
+            provider: ProviderKind::Twilio,
+            did: did.into(),
+            account: "AC00000000000000000000000000000000".into(),
+            secret: "own auth token".into(),
+        };
+        let public_key = credential::load_public_key(&opt.public_key()).unwrap();
+        cred.encrypt(&public_key, opt.keys.key_id()).unwrap()
+    }
+
+    /// Posts a message for `VICTIM` to `url`, signed as Twilio does with `auth_token`.
+    async fn inbound(opt: &Opt, url: &str, auth_token: &str) -> Result<(), VoipBitsError> {
+        let params: Vec<(String, String)> = vec![
+            ("MessageSid".into(), "SM1".into()),
+            ("From".into(), "+15145550000".into()),
+            ("To".into(), format!("+{}", VICTIM)),
+            ("Body".into(), "hi".into()),
+        ];
+        let mut mac = Hmac::<Sha1>::new_from_slice(auth_token.as_bytes()).unwrap();
+        mac.update(url.as_bytes());
+        let mut sorted = params.clone();
+        sorted.sort();
+        for (key, value) in sorted {
+            mac.update(key.as_bytes());
+            mac.update(value.as_bytes());
+        }
+        let mut headers = HeaderMap::new();
+        let signature = base64::encode(mac.finalize().into_bytes());
+        headers.insert("x-twilio-signature", signature.parse().unwrap());
+
+        let uri: Uri = url.strip_prefix(&opt.server_url).unwrap().parse().unwrap();
+        let param = |name: &str| {
+            uri.query()
+                .unwrap()
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .map(|value| urlencoding::decode(value).unwrap().into_owned())
+        };
+        let query = TwilioInboundQuery {
+            cred: param("cred").unwrap(),
+            sig: param("sig"),
+        };
+        let storage: Storage = std::sync::Arc::new(storage::MemoryStore::new());
+
+        twilio_inbound(
+            Extension(opt.clone()),
+            Extension(storage),
+            Ok(Query(query)),
+            uri,
+            headers,
+            Ok(Form(params)),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn twilio_inbound_for_someone_else() {
+        let opt = opt();
+        let cred = minted(&opt, VICTIM);
+
+        // As provisioned for the DID
+        let url = opt.twilio_inbound_url(VICTIM, &cred);
+        assert!(inbound(&opt, &url, "own auth token").await.is_ok());
+        assert!(matches!(
+            inbound(&opt, &url, "other auth token").await,
+            Err(VoipBitsError::InvalidSignature(_))
+        ));
+
+        // A credential minted for someone else's DID: Twilio signs right, but the URL
+        // has no signature of the DID, or the one of another DID
+        let unsigned = format!(
+            "{}/twilio/inbound?cred={}",
+            opt.server_url,
+            urlencoding::encode(&cred)
+        );
+        let own = opt.twilio_inbound_url("15145552222", &cred);
+        for url in &[unsigned, own] {
+            assert!(matches!(
+                inbound(&opt, url, "own auth token").await,
+                Err(VoipBitsError::InvalidSignature(_))
+            ));
+        }
+    }
+}
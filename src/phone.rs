@@ -0,0 +1,260 @@
+//! Destination numbers as typed into the softphone: E.164 (`+44 20 7946 0000`), with an
+//! international prefix (`011 44 ...`, `0044 ...`), national in the default country
+//! (`(514) 555-0000`, `020 7946 0000`) or short codes (`12345`).
+
+use crate::errors::VoipBitsError;
+use fehler::{throw, throws};
+use std::fmt;
+use std::str::FromStr;
+
+/// The country national numbers and short codes belong to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Country {
+    /// ISO 3166-1 alpha-2
+    pub iso: &'static str,
+    pub calling_code: &'static str,
+    /// Dialed before national numbers and dropped in E.164, `0` in most of Europe
+    trunk_prefix: Option<&'static str>,
+}
+
+const fn country(
+    iso: &'static str,
+    calling_code: &'static str,
+    trunk_prefix: Option<&'static str>,
+) -> Country {
+    Country {
+        iso,
+        calling_code,
+        trunk_prefix,
+    }
+}
+
+const COUNTRIES: &[Country] = &[
+    country("US", "1", None),
+    country("CA", "1", None),
+    country("PR", "1", None),
+    country("MX", "52", None),
+    country("BR", "55", Some("0")),
+    country("GB", "44", Some("0")),
+    country("IE", "353", Some("0")),
+    country("FR", "33", Some("0")),
+    country("DE", "49", Some("0")),
+    country("NL", "31", Some("0")),
+    country("BE", "32", Some("0")),
+    country("CH", "41", Some("0")),
+    country("AT", "43", Some("0")),
+    country("IT", "39", None),
+    country("ES", "34", None),
+    country("PT", "351", None),
+    country("SE", "46", Some("0")),
+    country("NO", "47", None),
+    country("DK", "45", None),
+    country("PL", "48", None),
+    country("IL", "972", Some("0")),
+    country("ZA", "27", Some("0")),
+    country("IN", "91", Some("0")),
+    country("CN", "86", Some("0")),
+    country("JP", "81", Some("0")),
+    country("HK", "852", None),
+    country("SG", "65", None),
+    country("PH", "63", Some("0")),
+    country("AU", "61", Some("0")),
+    country("NZ", "64", Some("0")),
+];
+
+impl Country {
+    /// The North American Numbering Plan: the US, Canada and most of the Caribbean.
+    pub fn is_nanp(&self) -> bool {
+        self.calling_code == "1"
+    }
+}
+
+impl FromStr for Country {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        COUNTRIES
+            .iter()
+            .find(|country| country.iso.eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(|| format!("unsupported country {}", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PhoneNumber {
+    /// The digits with the country code, without the `+`
+    E164(String),
+    /// Only reachable within `country`
+    ShortCode { country: Country, digits: String },
+}
+
+impl PhoneNumber {
+    /// Parses `input`, taking numbers without an international prefix as numbers of `country`.
+    #[throws(VoipBitsError)]
+    pub fn parse(input: &str, country: Country) -> PhoneNumber {
+        let invalid = |reason: &str| VoipBitsError::InvalidNumber(format!("{}: {}", input, reason));
+
+        let trimmed = input.trim();
+        let (plus, rest) = match trimmed.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed),
+        };
+
+        let mut digits = String::new();
+        for c in rest.chars() {
+            match c {
+                '0'..='9' => digits.push(c),
+                ' ' | '-' | '.' | '(' | ')' => {}
+                c => throw!(invalid(&format!("unexpected character {:?}", c))),
+            }
+        }
+        if digits.is_empty() {
+            throw!(invalid("no digits"));
+        }
+
+        let international = if plus {
+            Some(digits.as_str())
+        } else if country.is_nanp() {
+            digits.strip_prefix("011")
+        } else {
+            digits.strip_prefix("00")
+        };
+        if let Some(digits) = international {
+            return international_number(digits).map_err(invalid)?;
+        }
+
+        match digits.len() {
+            3..=6 if !country.is_nanp() => {
+                return PhoneNumber::ShortCode { country, digits };
+            }
+            5..=6 => return PhoneNumber::ShortCode { country, digits },
+            _ => {}
+        }
+
+        if country.is_nanp() {
+            let national = match digits.strip_prefix('1') {
+                Some(national) if digits.len() == 11 => national,
+                _ => &digits,
+            };
+            nanp_number(national).map_err(invalid)?
+        } else {
+            let national = match country.trunk_prefix {
+                Some(prefix) => digits.strip_prefix(prefix).unwrap_or(&digits),
+                None => &digits,
+            };
+            if national.len() < 4 {
+                throw!(invalid("too short"));
+            }
+            if country.calling_code.len() + national.len() > 15 {
+                throw!(invalid("too long"));
+            }
+            PhoneNumber::E164(format!("{}{}", country.calling_code, national))
+        }
+    }
+
+    /// The 10 digit national number of NANP numbers.
+    pub fn nanp_national(&self) -> Option<&str> {
+        match self {
+            PhoneNumber::E164(digits) => digits.strip_prefix('1'),
+            PhoneNumber::ShortCode { .. } => None,
+        }
+    }
+}
+
+/// E.164 for the carriers, the digits of short codes as is.
+impl fmt::Display for PhoneNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhoneNumber::E164(digits) => write!(f, "+{}", digits),
+            PhoneNumber::ShortCode { digits, .. } => f.write_str(digits),
+        }
+    }
+}
+
+/// The digits after `+`.
+fn international_number(digits: &str) -> Result<PhoneNumber, &'static str> {
+    if digits.starts_with('0') {
+        return Err("country codes do not start with 0");
+    }
+    if let Some(national) = digits.strip_prefix('1') {
+        return nanp_number(national);
+    }
+
+    match digits.len() {
+        0..=6 => Err("too short for an international number"),
+        7..=15 => Ok(PhoneNumber::E164(digits.into())),
+        _ => Err("longer than the 15 digits of E.164"),
+    }
+}
+
+/// The 10 digits after the `1` of NANP numbers.
+fn nanp_number(national: &str) -> Result<PhoneNumber, &'static str> {
+    if national.len() != 10 {
+        return Err("North American numbers have 10 digits after the country code");
+    }
+    if national.starts_with(&['0', '1'][..]) {
+        return Err("North American area codes do not start with 0 or 1");
+    }
+    if national[3..].starts_with(&['0', '1'][..]) {
+        return Err("North American exchange codes do not start with 0 or 1");
+    }
+
+    Ok(PhoneNumber::E164(format!("1{}", national)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str, country: &str) -> Result<String, VoipBitsError> {
+        PhoneNumber::parse(input, country.parse().unwrap()).map(|number| number.to_string())
+    }
+
+    #[test]
+    fn nanp() {
+        assert_eq!(parse("(514) 555-0000", "CA").unwrap(), "+15145550000");
+        assert_eq!(parse("1 514 555 0000", "US").unwrap(), "+15145550000");
+        assert_eq!(parse("+1.514.555.0000", "GB").unwrap(), "+15145550000");
+        assert!(parse("555-0000", "US").is_err());
+        assert!(parse("(014) 555-0000", "US").is_err());
+        assert!(parse("514 155 0000", "US").is_err());
+    }
+
+    #[test]
+    fn international() {
+        assert_eq!(parse("+44 20 7946 0000", "US").unwrap(), "+442079460000");
+        assert_eq!(parse("011 44 20 7946 0000", "US").unwrap(), "+442079460000");
+        assert_eq!(parse("0044 20 7946 0000", "FR").unwrap(), "+442079460000");
+        assert!(parse("+44 20", "US").is_err());
+        assert!(parse("+0 20 7946 0000", "US").is_err());
+        assert!(parse("+44 2079 4600 0000 0000", "US").is_err());
+    }
+
+    #[test]
+    fn national() {
+        assert_eq!(parse("020 7946 0000", "GB").unwrap(), "+442079460000");
+        assert_eq!(parse("06 12 34 56 78", "FR").unwrap(), "+33612345678");
+        assert_eq!(parse("06 1234 5678", "IT").unwrap(), "+390612345678");
+    }
+
+    #[test]
+    fn short_codes() {
+        assert_eq!(
+            PhoneNumber::parse("12345", "US".parse().unwrap()).unwrap(),
+            PhoneNumber::ShortCode {
+                country: "US".parse().unwrap(),
+                digits: "12345".into()
+            }
+        );
+        assert_eq!(parse("3223", "FR").unwrap(), "3223");
+        assert!(parse("123", "US").is_err());
+    }
+
+    #[test]
+    fn garbage() {
+        assert!(parse("", "US").is_err());
+        assert!(parse("+", "US").is_err());
+        assert!(parse("514-CALL-NOW", "US").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::acrobits::AcrobitsSMS;
+use crate::credential::{Credential, ProviderKind};
+use crate::storage::Storage;
+use crate::telnyx::Telnyx;
+use crate::twilio::Twilio;
+use crate::voipms::VoipMS;
+use crate::Opt;
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use fehler::throws;
+
+/// A carrier that VoipBits can send and receive messages through.
+#[async_trait]
+pub trait SmsProvider: Send + Sync {
+    fn did(&self) -> &str;
+
+    /// Sends the message with optional media URLs.
+    async fn send(&self, dst: &str, msg: &str, media: &[String]) -> Result<Sent, Error>;
+
+    /// Messages since (and possibly including) the message `last_id`.
+    async fn fetch_after_id(&self, last_id: &str) -> Result<Vec<AcrobitsSMS>, Error>;
+
+    /// Messages since `from`, or as far back as the carrier keeps them.
+    async fn fetch_from_date(&self, from: Option<DateTime<Utc>>)
+        -> Result<Vec<AcrobitsSMS>, Error>;
+
+    /// Confirms with the carrier that the credential works and the DID is on the account,
+    /// for the requests that only read what VoipBits keeps about the DID.
+    async fn verify(&self) -> Result<(), Error>;
+
+    /// Points the carrier's inbound message webhook at VoipBits.
+    async fn register_callback(&self, opt: &Opt) -> Result<(), Error>;
+
+    /// The SIP account that rings for the DID, so that provisioning sets up calls too.
+    /// `pop` picks the server the softphone registers to, where the carrier has several.
+    async fn sip_account(&self, _pop: Option<&str>) -> Result<Option<SipAccount>, Error> {
+        Ok(None)
+    }
+}
+
+/// The outcome of `SmsProvider::send`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sent {
+    /// The ids of the messages the text was sent as
+    pub ids: Vec<String>,
+    /// How many SMS or MMS the carrier bills for it
+    pub segments: usize,
+}
+
+pub struct SipAccount {
+    pub username: String,
+    pub password: String,
+    pub domain: String,
+    pub voicemail_number: Option<String>,
+}
+
+/// Decrypts the credential and builds the provider it belongs to.
+#[throws(Error)]
+pub fn from_cred(opt: &Opt, storage: &Storage, cred: &str) -> Box<dyn SmsProvider> {
+    let blob = cred;
+    let cred = Credential::decrypt(&opt.keys, blob)?;
+
+    let provider: Box<dyn SmsProvider> = match cred.provider {
+        ProviderKind::VoipMS => Box::new(
+            VoipMS::new(
+                &cred.account,
+                &cred.secret,
+                &cred.did,
+                opt.voipms_timezone,
+                opt.default_country,
+                opt.voipms_international_sms,
+                opt.segment_markers,
+            )
+            .with_api_url(&opt.voipms_api_url),
+        ),
+        ProviderKind::Twilio => Box::new(Twilio::new(
+            &cred.account,
+            &cred.secret,
+            &cred.did,
+            blob,
+            &opt.twilio_api_url,
+            opt.default_country,
+        )),
+        ProviderKind::Telnyx => Box::new(Telnyx::new(
+            &cred.secret,
+            &cred.account,
+            &cred.did,
+            storage.clone(),
+            &opt.telnyx_api_url,
+            opt.default_country,
+        )),
+    };
+    provider
+}
@@ -0,0 +1,283 @@
+//! The `<account>` document of Acrobits external provisioning, see
+//! <https://doc.acrobits.net/cloudsoftphone/account.html>.
+
+use crate::errors::VoipBitsError;
+use crate::provider::SipAccount;
+use crate::Opt;
+use fehler::{throw, throws};
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use serde_json::Value;
+use std::fs;
+use std::io::Cursor;
+
+const DEFAULT_VOICEMAIL_NUMBER: &str = "*97";
+
+/// A softphone account: the SIP account, when provisioned, and the web services
+/// that make SMS go through VoipBits.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub sip: Option<Sip>,
+    pub push_token_reporter: WebService,
+    pub sms_fetch: WebService,
+    pub sms_send: WebService,
+    pub voipms_notification_url: String,
+    pub allow_message: bool,
+    pub voicemail_number: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sip {
+    pub title: String,
+    pub username: String,
+    pub password: String,
+    pub host: String,
+}
+
+/// A web service the softphone calls, with the credential as the request body.
+#[derive(Debug, Clone)]
+pub struct WebService {
+    pub url: String,
+    pub post_data: String,
+    pub content_type: String,
+}
+
+impl WebService {
+    fn with_cred(url: String, cred: &str) -> WebService {
+        WebService {
+            url,
+            post_data: cred.into(),
+            content_type: "text/plain".into(),
+        }
+    }
+}
+
+impl Account {
+    pub fn new(opt: &Opt, did: &str, cred: &str, sip: Option<SipAccount>) -> Account {
+        let voicemail_number = sip
+            .as_ref()
+            .and_then(|sip| sip.voicemail_number.clone())
+            .unwrap_or_else(|| DEFAULT_VOICEMAIL_NUMBER.into());
+
+        Account {
+            sip: sip.map(|sip| Sip {
+                title: did.into(),
+                username: sip.username,
+                password: sip.password,
+                host: sip.domain,
+            }),
+            push_token_reporter: WebService::with_cred(opt.report_url(), cred),
+            sms_fetch: WebService::with_cred(opt.fetch_url(), cred),
+            sms_send: WebService::with_cred(opt.send_url(), cred),
+            voipms_notification_url: opt.notify_url(did),
+            allow_message: true,
+            voicemail_number,
+        }
+    }
+
+    /// The settings in document order, under their Acrobits names.
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = vec![];
+
+        if let Some(sip) = &self.sip {
+            settings.push(("title", sip.title.clone()));
+            settings.push(("username", sip.username.clone()));
+            settings.push(("password", sip.password.clone()));
+            settings.push(("host", sip.host.clone()));
+        }
+
+        let reporter = &self.push_token_reporter;
+        settings.push(("pushTokenReporterUrl", reporter.url.clone()));
+        settings.push(("pushTokenReporterPostData", reporter.post_data.clone()));
+        settings.push((
+            "pushTokenReporterContentType",
+            reporter.content_type.clone(),
+        ));
+
+        let fetch = &self.sms_fetch;
+        settings.push(("genericSmsFetchUrl", fetch.url.clone()));
+        settings.push(("genericSmsFetchPostData", fetch.post_data.clone()));
+        settings.push(("genericSmsFetchContentType", fetch.content_type.clone()));
+
+        let send = &self.sms_send;
+        settings.push(("genericSmsSendUrl", send.url.clone()));
+        settings.push(("genericSmsPostData", send.post_data.clone()));
+        settings.push(("genericSmsContentType", send.content_type.clone()));
+
+        settings.push((
+            "voipmsNotificationUrl",
+            self.voipms_notification_url.clone(),
+        ));
+        settings.push(("allowMessage", flag(self.allow_message)));
+        settings.push(("voiceMailNumber", self.voicemail_number.clone()));
+
+        settings
+    }
+
+    /// Serializes the account, with the deployment overrides applied on top.
+    #[throws(VoipBitsError)]
+    pub fn to_xml(&self, overrides: Option<&Overrides>) -> String {
+        let mut settings: Vec<(String, String)> = self
+            .settings()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        if let Some(overrides) = overrides {
+            overrides.apply(&mut settings);
+        }
+
+        let mut writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
+        write_document(&mut writer, &settings).map_err(|e| {
+            VoipBitsError::Internal(format!("cannot write provisioning XML: {}", e))
+        })?;
+
+        String::from_utf8(writer.into_inner().into_inner())
+            .map_err(|e| VoipBitsError::Internal(e.to_string()))?
+    }
+}
+
+fn write_document(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    settings: &[(String, String)],
+) -> quick_xml::Result<()> {
+    writer.write_event(Event::Start(BytesStart::borrowed_name(b"account")))?;
+    for (name, value) in settings {
+        writer.write_event(Event::Start(BytesStart::borrowed_name(name.as_bytes())))?;
+        writer.write_event(Event::Text(BytesText::from_plain_str(value)))?;
+        writer.write_event(Event::End(BytesEnd::borrowed(name.as_bytes())))?;
+    }
+    writer.write_event(Event::End(BytesEnd::borrowed(b"account")))?;
+
+    Ok(())
+}
+
+fn flag(on: bool) -> String {
+    if on { "1" } else { "0" }.into()
+}
+
+/// Extra Acrobits settings of a deployment, from the JSON object in `PROVISIONING_CONFIG`,
+/// e.g. `{"allowMessage": true, "codecs": "opus,g722", "voipmsNotificationUrl": null}`.
+///
+/// A setting replaces the one VoipBits generates under the same name, `null` drops it,
+/// and unknown names are appended to the account.
+#[derive(Debug, Clone)]
+pub struct Overrides(Vec<(String, Option<String>)>);
+
+impl Overrides {
+    /// Loads the config file at `path`, for `parse(try_from_str)`.
+    pub fn load(path: &str) -> Result<Overrides, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read provisioning config {}: {}", path, e))?;
+
+        Overrides::parse(&content).map_err(|e| format!("invalid provisioning config: {}", e))
+    }
+
+    #[throws(VoipBitsError)]
+    fn parse(content: &str) -> Overrides {
+        let settings = match serde_json::from_str(content) {
+            Ok(Value::Object(settings)) => settings,
+            Ok(_) => throw!(VoipBitsError::Validation("expected a JSON object".into())),
+            Err(e) => throw!(VoipBitsError::Validation(e.to_string())),
+        };
+
+        let mut overrides = vec![];
+        for (name, value) in settings {
+            if !is_setting_name(&name) {
+                throw!(VoipBitsError::Validation(format!(
+                    "{:?} is not a setting name",
+                    name
+                )));
+            }
+
+            let value = match value {
+                Value::Null => None,
+                Value::Bool(on) => Some(flag(on)),
+                Value::Number(n) => Some(n.to_string()),
+                Value::String(s) => Some(s),
+                _ => throw!(VoipBitsError::Validation(format!(
+                    "the value of {} is not a string, number, boolean or null",
+                    name
+                ))),
+            };
+            overrides.push((name, value));
+        }
+
+        Overrides(overrides)
+    }
+
+    fn apply(&self, settings: &mut Vec<(String, String)>) {
+        for (name, value) in &self.0 {
+            let existing = settings.iter().position(|(n, _)| n == name);
+            match (existing, value) {
+                (Some(pos), Some(value)) => settings[pos].1 = value.clone(),
+                (Some(pos), None) => {
+                    settings.remove(pos);
+                }
+                (None, Some(value)) => settings.push((name.clone(), value.clone())),
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+/// Acrobits settings are plain element names.
+fn is_setting_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account() -> Account {
+        let service = |url: &str| WebService {
+            url: url.into(),
+            post_data: "v2:1:<a&b>:\"c\"".into(),
+            content_type: "text/plain".into(),
+        };
+
+        Account {
+            sip: None,
+            push_token_reporter: service("https://example.com/report?token=%pushToken%&appid=1"),
+            sms_fetch: service("https://example.com/fetch"),
+            sms_send: service("https://example.com/send"),
+            voipms_notification_url: "https://example.com/notify?from={FROM}&to={TO}".into(),
+            allow_message: true,
+            voicemail_number: "*97".into(),
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        let xml = account().to_xml(None).unwrap();
+
+        assert!(xml.starts_with("<account>"));
+        assert!(xml.contains(
+            "<pushTokenReporterUrl>https://example.com/report?token=%pushToken%&amp;appid=1</pushTokenReporterUrl>"
+        ));
+        assert!(xml.contains(
+            "<genericSmsPostData>v2:1:&lt;a&amp;b&gt;:&quot;c&quot;</genericSmsPostData>"
+        ));
+        assert!(!xml.contains("<username>"));
+    }
+
+    #[test]
+    fn overrides() {
+        let overrides = Overrides::parse(
+            r#"{"voiceMailNumber": "*98", "allowMessage": null, "codecs": "opus", "dtmfOrder": 1}"#,
+        )
+        .unwrap();
+        let xml = account().to_xml(Some(&overrides)).unwrap();
+
+        assert!(xml.contains("<voiceMailNumber>*98</voiceMailNumber>"));
+        assert!(!xml.contains("allowMessage"));
+        assert!(xml.contains("<codecs>opus</codecs>"));
+        assert!(xml.contains("<dtmfOrder>1</dtmfOrder>"));
+
+        assert!(Overrides::parse(r#"{"a><b": "x"}"#).is_err());
+        assert!(Overrides::parse(r#"{"codecs": ["opus"]}"#).is_err());
+        assert!(Overrides::parse("[]").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Joining back the parts of long inbound texts, which carriers like voip.ms deliver
+//! as separate messages a few seconds apart.
+
+use crate::acrobits::AcrobitsSMS;
+use crate::segment;
+use chrono::Duration;
+
+/// Merges each received message into the previous one when it comes from the same sender
+/// within `window` and the previous one fills a whole SMS. `smss` are in the order they
+/// were sent. The merged message takes the id of its last part, so that fetching after
+/// it does not return the parts again, and the date of its first part.
+pub fn reassemble(smss: Vec<AcrobitsSMS>, window: Duration) -> Vec<AcrobitsSMS> {
+    let mut merged: Vec<AcrobitsSMS> = Vec::with_capacity(smss.len());
+    // The last part of the last merged message
+    let mut last_part: Option<AcrobitsSMS> = None;
+
+    for sms in smss {
+        if let (Some(last), Some(part)) = (merged.last_mut(), &last_part) {
+            if continues(part, &sms, window) {
+                last.sms_id = sms.sms_id.clone();
+                last.sms_text.push_str(&sms.sms_text);
+                last_part = Some(sms);
+                continue;
+            }
+        }
+        last_part = Some(sms.clone());
+        merged.push(sms);
+    }
+
+    merged
+}
+
+fn continues(prev: &AcrobitsSMS, next: &AcrobitsSMS, window: Duration) -> bool {
+    prev.sender.is_some()
+        && prev.sender == next.sender
+        && prev.attachments.is_empty()
+        && next.attachments.is_empty()
+        && next.sending_date >= prev.sending_date
+        && next.sending_date - prev.sending_date <= window
+        && segment::is_fragment(&prev.sms_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn sms(id: &str, secs: i64, sender: &str, text: &str) -> AcrobitsSMS {
+        AcrobitsSMS {
+            sms_id: id.into(),
+            sending_date: Utc.timestamp_opt(1646128800 + secs, 0).unwrap(),
+            sender: Some(sender.into()),
+            recipient: None,
+            sms_text: text.into(),
+            attachments: vec![],
+        }
+    }
+
+    #[test]
+    fn fragments() {
+        let first = "a".repeat(153);
+        let smss = vec![
+            sms("1", 0, "5145550000", &first),
+            sms("2", 1, "5145550000", &"b".repeat(153)),
+            sms("3", 2, "5145550000", "end"),
+            sms("4", 3, "5145550000", "another text"),
+        ];
+        let merged = reassemble(smss, Duration::seconds(10));
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].sms_id, "3");
+        assert_eq!(merged[0].sms_text.len(), 153 + 153 + 3);
+        assert_eq!(
+            merged[0].sending_date,
+            Utc.timestamp_opt(1646128800, 0).unwrap()
+        );
+        assert_eq!(merged[1].sms_text, "another text");
+    }
+
+    #[test]
+    fn separate() {
+        let full = "a".repeat(160);
+        let smss = vec![
+            sms("1", 0, "5145550000", &full),
+            // Another sender
+            sms("2", 1, "5145551111", "x"),
+            sms("3", 2, "5145551111", &full),
+            // Too late
+            sms("4", 30, "5145551111", "y"),
+        ];
+
+        assert_eq!(reassemble(smss, Duration::seconds(10)).len(), 4);
+    }
+}
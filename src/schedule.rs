@@ -0,0 +1,286 @@
+//! Messages sent later: typed in the softphone as `@at 2026-11-01 09:00 text`,
+//! `@at 18:30 text` or `@in 1h30m text`, or queued through `/schedule`. The wall clock
+//! times are in `SCHEDULE_TIMEZONE`.
+//!
+//! The local server checks for due messages every `SCHEDULE_INTERVAL` seconds. On Lambda,
+//! a scheduled invocation of `/schedule/tick` does.
+
+use crate::acrobits::Acrobits;
+use crate::errors::VoipBitsError;
+use crate::provider;
+use crate::storage::{ScheduledMessage, Storage};
+use crate::voipms::account_time;
+use crate::Opt;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use fehler::{throw, throws};
+use std::time::Duration as StdDuration;
+use tracing::{info, warn};
+
+/// Splits the `@at`/`@in` prefix off `text`, returning when to send the rest.
+/// Texts without a prefix, or with one not followed by a time, are to be sent now.
+#[throws(VoipBitsError)]
+pub fn parse_prefix(text: &str, now: DateTime<Utc>, timezone: Tz) -> Option<(DateTime<Utc>, &str)> {
+    let text = text.trim_start();
+    let (command, rest) = match text.split_once(char::is_whitespace) {
+        Some((command, rest)) => (command, rest.trim_start()),
+        None => (text, ""),
+    };
+
+    // Without a valid time after it, the prefix is just the start of the text
+    let (send_at, rest) = match command.to_ascii_lowercase().as_str() {
+        "@at" => {
+            // The date is optional, the time is not
+            let (first, after_first) = split_word(rest);
+            let (send_at, rest) = match NaiveDate::parse_from_str(first, "%Y-%m-%d") {
+                Ok(date) => {
+                    let (time, after_time) = split_word(after_first);
+                    let time = parse_clock(time).ok();
+                    let send_at = time.map(|time| account_time(timezone, date.and_time(time)));
+                    (send_at, after_time)
+                }
+                Err(_) => {
+                    let time = parse_clock(first).ok();
+                    (time.map(|time| next_time(time, now, timezone)), after_first)
+                }
+            };
+            match send_at {
+                Some(send_at) => (send_at, rest),
+                None => return None,
+            }
+        }
+        "@in" => {
+            let (delay, after_delay) = split_word(rest);
+            match parse_delay(delay) {
+                Ok(delay) => (now + delay, after_delay),
+                Err(_) => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    if send_at <= now {
+        throw!(VoipBitsError::Validation(format!(
+            "{} is in the past",
+            send_at.with_timezone(&timezone)
+        )));
+    }
+    if rest.trim().is_empty() {
+        throw!(VoipBitsError::EmptyMessage);
+    }
+
+    Some((send_at, rest))
+}
+
+/// The `at` of `/schedule`: RFC 3339, `2026-11-01 09:00` or `09:00`, the latter two in
+/// the schedule timezone.
+#[throws(VoipBitsError)]
+pub fn parse_time(at: &str, now: DateTime<Utc>, timezone: Tz) -> DateTime<Utc> {
+    let at = at.trim();
+    let send_at = if let Ok(time) = DateTime::parse_from_rfc3339(at) {
+        time.with_timezone(&Utc)
+    } else if let Ok(time) = NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M") {
+        account_time(timezone, time)
+    } else {
+        next_time(parse_clock(at)?, now, timezone)
+    };
+
+    if send_at <= now {
+        throw!(VoipBitsError::Validation(format!("{} is in the past", at)));
+    }
+    send_at
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (text, ""),
+    }
+}
+
+#[throws(VoipBitsError)]
+fn parse_clock(time: &str) -> NaiveTime {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| VoipBitsError::Validation(format!("{:?} is not a time like 09:00", time)))?
+}
+
+/// The next time the clock of `timezone` shows `time`, today or tomorrow.
+fn next_time(time: NaiveTime, now: DateTime<Utc>, timezone: Tz) -> DateTime<Utc> {
+    let today = now.with_timezone(&timezone).naive_local().date();
+    let send_at = account_time(timezone, today.and_time(time));
+    if send_at > now {
+        send_at
+    } else {
+        account_time(timezone, (today + Duration::days(1)).and_time(time))
+    }
+}
+
+/// Delays such as `45m`, `2h` or `1d12h`.
+#[throws(VoipBitsError)]
+fn parse_delay(delay: &str) -> Duration {
+    let invalid = || VoipBitsError::Validation(format!("{:?} is not a delay like 1h30m", delay));
+
+    let mut total = Duration::zero();
+    let mut digits = String::new();
+    for c in delay.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let n: i64 = digits.parse().map_err(|_| invalid())?;
+        total += match c.to_ascii_lowercase() {
+            'd' => Duration::days(n),
+            'h' => Duration::hours(n),
+            'm' => Duration::minutes(n),
+            _ => throw!(invalid()),
+        };
+        digits.clear();
+    }
+    if !digits.is_empty() || delay.is_empty() {
+        throw!(invalid());
+    }
+
+    total
+}
+
+/// Sends the messages due at `now` and pushes how each went to the devices of its DID.
+/// Returns how many were sent.
+#[throws(VoipBitsError)]
+pub async fn deliver_due(opt: &Opt, storage: &Storage, now: DateTime<Utc>) -> usize {
+    let mut sent = 0;
+
+    for message in storage.due_messages(now).await? {
+        if !storage.take_scheduled(&message.did, &message.id).await? {
+            // Taken by another run
+            continue;
+        }
+
+        let report = match deliver(opt, storage, &message).await {
+            Ok(()) => {
+                info!(
+                    "[schedule] Sent {} ({} -> {})",
+                    message.id, message.did, message.to
+                );
+                sent += 1;
+                format!("Scheduled message sent: {}", message.text)
+            }
+            Err(e) => {
+                warn!(
+                    "[schedule] Cannot send {} ({} -> {}): {}",
+                    message.id, message.did, message.to, e
+                );
+                format!("Scheduled message not sent ({}): {}", e, message.text)
+            }
+        };
+
+        if let Err(e) = Acrobits::new()
+            .notify_devices(storage, &message.did, &message.to, &report)
+            .await
+        {
+            warn!(
+                "[schedule] Cannot push the outcome of {}: {}",
+                message.id, e
+            );
+        }
+    }
+
+    sent
+}
+
+#[throws(VoipBitsError)]
+async fn deliver(opt: &Opt, storage: &Storage, message: &ScheduledMessage) {
+    let provider = provider::from_cred(opt, storage, &message.cred)?;
+    provider
+        .send(&message.to, &message.text, &message.media)
+        .await?;
+}
+
+/// The scheduler of the local server.
+pub async fn run(opt: Opt, storage: Storage) {
+    let mut interval = tokio::time::interval(StdDuration::from_secs(opt.schedule_interval));
+    loop {
+        interval.tick().await;
+        if let Err(e) = deliver_due(&opt, &storage, Utc::now()).await {
+            warn!("[schedule] Cannot deliver the due messages: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        // 10:00 in New York
+        Utc.timestamp_opt(1_776_261_600, 0).unwrap()
+    }
+
+    fn parse(text: &str) -> Result<Option<(DateTime<Utc>, &str)>, VoipBitsError> {
+        parse_prefix(text, now(), chrono_tz::America::New_York)
+    }
+
+    #[test]
+    fn at() {
+        let (send_at, text) = parse("@at 2026-11-01 09:00 see you").unwrap().unwrap();
+        assert_eq!(send_at.to_rfc3339(), "2026-11-01T14:00:00+00:00");
+        assert_eq!(text, "see you");
+
+        let (send_at, _) = parse("@AT 18:30 dinner?").unwrap().unwrap();
+        assert_eq!(send_at, now() + Duration::minutes(8 * 60 + 30));
+
+        // Already past today
+        let (send_at, _) = parse("@at 09:00 coffee").unwrap().unwrap();
+        assert_eq!(send_at, now() + Duration::hours(23));
+    }
+
+    #[test]
+    fn delay() {
+        let (send_at, text) = parse("@in 1h30m  call me").unwrap().unwrap();
+        assert_eq!(send_at, now() + Duration::minutes(90));
+        assert_eq!(text, "call me");
+
+        assert_eq!(parse("@in 2x hi").unwrap(), None);
+        assert_eq!(parse("@in 30 hi").unwrap(), None);
+        assert!(parse("@in 0m hi").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse("@at 2020-01-01 09:00 too late").is_err());
+        assert_eq!(parse("@at 25:00 hi").unwrap(), None);
+        assert_eq!(parse("@at 2026-11-01 noon hi").unwrap(), None);
+        assert!(matches!(
+            parse("@in 5m  "),
+            Err(VoipBitsError::EmptyMessage)
+        ));
+    }
+
+    #[test]
+    fn no_prefix() {
+        assert_eq!(parse("see you @at 9").unwrap(), None);
+        assert_eq!(parse("@atlas").unwrap(), None);
+        assert_eq!(parse("@in the car, call you later").unwrap(), None);
+        assert_eq!(parse("@at home").unwrap(), None);
+        assert_eq!(parse("").unwrap(), None);
+    }
+
+    #[test]
+    fn api_time() {
+        let tz = chrono_tz::America::New_York;
+        assert_eq!(
+            parse_time("2026-11-01T09:00:00Z", now(), tz)
+                .unwrap()
+                .to_rfc3339(),
+            "2026-11-01T09:00:00+00:00"
+        );
+        assert_eq!(
+            parse_time("2026-11-01 09:00", now(), tz)
+                .unwrap()
+                .to_rfc3339(),
+            "2026-11-01T14:00:00+00:00"
+        );
+        assert!(parse_time("yesterday", now(), tz).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! Searching the message log, which holds the archived messages and those of the
+//! carriers without a message history.
+//!
+//! A message matches a text query when it has every word of the query, in any case.
+//! Numbers match a contact whatever their formatting, `+1 (514) 555-0000` being the same
+//! contact as `5145550000`.
+
+use crate::acrobits::AcrobitsSMS;
+use std::collections::BTreeSet;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// How many messages a search returns without a `limit`
+pub const DEFAULT_LIMIT: usize = 50;
+
+/// How many messages a search returns at most
+pub const MAX_LIMIT: usize = 500;
+
+/// The distinct words of `text`, lowercased, which the index is keyed by.
+pub fn words(text: &str) -> Vec<String> {
+    let words: BTreeSet<_> = text.unicode_words().map(str::to_lowercase).collect();
+    words.into_iter().collect()
+}
+
+/// Whether `text` has every one of `words`.
+pub fn has_words(text: &str, words: &[String]) -> bool {
+    let found = self::words(text);
+    words.iter().all(|word| found.contains(word))
+}
+
+/// Whether the message was sent to or received from `contact`.
+pub fn with_contact(sms: &AcrobitsSMS, contact: &str) -> bool {
+    let contact = digits(contact);
+    let other = sms.sender.as_deref().or(sms.recipient.as_deref());
+
+    match other {
+        Some(other) => same_number(&digits(other), &contact),
+        None => false,
+    }
+}
+
+fn digits(number: &str) -> String {
+    number.chars().filter(char::is_ascii_digit).collect()
+}
+
+/// Numbers compare without their country code, which carriers give or not.
+fn same_number(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    match long.strip_suffix(short) {
+        Some(prefix) => short.len() >= 7 && prefix.len() <= 3,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn matching_words() {
+        assert_eq!(
+            words("See you at the café, see?"),
+            vec!["at", "café", "see", "the", "you"]
+        );
+        assert!(has_words("Dinner at 7 tonight?", &words("tonight DINNER")));
+        assert!(!has_words(
+            "Dinner at 7 tonight?",
+            &words("dinner tomorrow")
+        ));
+        assert!(!has_words("dinners", &words("dinner")));
+    }
+
+    #[test]
+    fn contacts() {
+        let sms = AcrobitsSMS {
+            sms_id: "1".into(),
+            sending_date: Utc::now(),
+            sender: Some("5145550000".into()),
+            recipient: None,
+            sms_text: "hi".into(),
+            attachments: vec![],
+        };
+
+        assert!(with_contact(&sms, "+1 (514) 555-0000"));
+        assert!(with_contact(&sms, "514.555.0000"));
+        assert!(!with_contact(&sms, "5145550001"));
+        assert!(!with_contact(&sms, "0000"));
+    }
+}
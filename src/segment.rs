@@ -0,0 +1,244 @@
+//! Splitting message texts into SMS.
+//!
+//! A text made of GSM 03.38 characters is sent as GSM-7 and fits 160 characters in one SMS,
+//! the characters of the extension table taking two. Anything else makes the whole text UCS-2,
+//! where an SMS fits 70 UTF-16 code units. Concatenated SMS lose some room to the header
+//! that joins them: 153 GSM-7 characters or 67 UCS-2 code units each.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+const GSM7_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+    ¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+
+/// Sent as an escape and the character
+const GSM7_EXTENSION: &str = "^{}\\[~]|€\x0c";
+
+/// How much shorter than a full part a fragment can be
+const FRAGMENT_SLACK: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gsm7,
+    Ucs2,
+}
+
+impl Encoding {
+    pub fn detect(text: &str) -> Encoding {
+        let gsm7 = text
+            .chars()
+            .all(|c| GSM7_BASIC.contains(c) || GSM7_EXTENSION.contains(c));
+        if gsm7 {
+            Encoding::Gsm7
+        } else {
+            Encoding::Ucs2
+        }
+    }
+
+    /// Room of a single SMS
+    fn single(self) -> usize {
+        match self {
+            Encoding::Gsm7 => 160,
+            Encoding::Ucs2 => 70,
+        }
+    }
+
+    /// Room of each part of a concatenated SMS
+    fn concatenated(self) -> usize {
+        match self {
+            Encoding::Gsm7 => 153,
+            Encoding::Ucs2 => 67,
+        }
+    }
+
+    /// The room `text` takes, in septets or UTF-16 code units.
+    fn cost(self, text: &str) -> usize {
+        match self {
+            Encoding::Gsm7 => text
+                .chars()
+                .map(|c| if GSM7_EXTENSION.contains(c) { 2 } else { 1 })
+                .sum(),
+            Encoding::Ucs2 => text.encode_utf16().count(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Segments {
+    pub encoding: Encoding,
+    pub parts: Vec<String>,
+}
+
+/// Splits `text` into standalone SMS, between words where possible and never inside
+/// a grapheme cluster, so that an emoji sequence or an accented letter stays whole.
+/// With `markers`, each part ends with ` (i/n)` when there are several.
+pub fn split(text: &str, markers: bool) -> Segments {
+    let encoding = Encoding::detect(text);
+    let room = encoding.single();
+
+    if encoding.cost(text) <= room {
+        return Segments {
+            encoding,
+            parts: vec![text.into()],
+        };
+    }
+    if !markers {
+        return Segments {
+            encoding,
+            parts: pack(text, encoding, room),
+        };
+    }
+
+    // The marker takes ` (` + `/` + `)` and the digits of i and n out of every part
+    let mut digits = 1;
+    let parts = loop {
+        let parts = pack(text, encoding, room - (4 + 2 * digits));
+        if parts.len() < 10usize.pow(digits as u32) {
+            break parts;
+        }
+        digits += 1;
+    };
+
+    let total = parts.len();
+    Segments {
+        encoding,
+        parts: parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, part)| format!("{} ({}/{})", part, i + 1, total))
+            .collect(),
+    }
+}
+
+/// How many SMS a carrier that concatenates long texts bills for `text`.
+pub fn count(text: &str) -> usize {
+    let encoding = Encoding::detect(text);
+    let cost = encoding.cost(text);
+
+    if cost <= encoding.single() {
+        1
+    } else {
+        cost.div_ceil(encoding.concatenated())
+    }
+}
+
+/// Whether `text` fills an SMS or a part of a concatenated SMS, so that the text likely
+/// goes on in the next message. Carriers sometimes drop the spaces around the cut.
+pub fn is_fragment(text: &str) -> bool {
+    let encoding = Encoding::detect(text);
+    encoding.cost(text) + FRAGMENT_SLACK >= encoding.concatenated()
+}
+
+/// Fills parts of at most `room` with whole words, falling back to whole graphemes for
+/// words longer than a part. Parts do not start or end with whitespace.
+fn pack(text: &str, encoding: Encoding, room: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut used = 0;
+
+    for word in text.split_word_bounds() {
+        let cost = encoding.cost(word);
+        if used + cost <= room {
+            part.push_str(word);
+            used += cost;
+            continue;
+        }
+
+        if !part.trim().is_empty() {
+            parts.push(part.trim_end().to_string());
+        }
+        part = String::new();
+        used = 0;
+
+        if word.trim().is_empty() {
+            continue;
+        }
+        if cost <= room {
+            part.push_str(word);
+            used = cost;
+            continue;
+        }
+
+        for grapheme in word.graphemes(true) {
+            let cost = encoding.cost(grapheme);
+            if used + cost > room && !part.is_empty() {
+                parts.push(std::mem::take(&mut part));
+                used = 0;
+            }
+            part.push_str(grapheme);
+            used += cost;
+        }
+    }
+
+    if !part.trim().is_empty() {
+        parts.push(part.trim_end().to_string());
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        assert_eq!(Encoding::detect("Hello @ 5€ [ok]"), Encoding::Gsm7);
+        assert_eq!(Encoding::detect("Ça coûte 5€"), Encoding::Ucs2);
+        assert_eq!(Encoding::detect("hi 👋"), Encoding::Ucs2);
+        assert_eq!(Encoding::Gsm7.cost("a€{"), 5);
+        assert_eq!(Encoding::Ucs2.cost("a👋"), 3);
+    }
+
+    #[test]
+    fn single() {
+        let text = "a".repeat(160);
+        assert_eq!(split(&text, true).parts, vec![text.clone()]);
+        assert_eq!(count(&text), 1);
+
+        // Extension characters take two septets
+        assert_eq!(split(&"€".repeat(81), false).parts.len(), 2);
+    }
+
+    #[test]
+    fn words() {
+        let text = "word ".repeat(50);
+        let segments = split(text.trim(), false);
+
+        assert_eq!(segments.encoding, Encoding::Gsm7);
+        assert_eq!(segments.parts.len(), 2);
+        assert!(segments.parts.iter().all(|part| part.len() <= 160));
+        assert!(segments.parts.iter().all(|part| part.ends_with("word")));
+        assert_eq!(segments.parts.join(" "), text.trim());
+    }
+
+    #[test]
+    fn graphemes() {
+        // A family emoji is 11 UTF-16 code units in one grapheme
+        let family = "👨‍👩‍👧‍👦";
+        let text = family.repeat(10);
+        let segments = split(&text, false);
+
+        assert_eq!(segments.encoding, Encoding::Ucs2);
+        assert_eq!(segments.parts.len(), 2);
+        assert_eq!(segments.parts[0], family.repeat(6));
+        assert_eq!(segments.parts[1], family.repeat(4));
+    }
+
+    #[test]
+    fn markers() {
+        let text = "word ".repeat(100);
+        let segments = split(text.trim(), true);
+
+        assert_eq!(segments.parts.len(), 4);
+        assert!(segments.parts[0].ends_with("word (1/4)"));
+        assert!(segments.parts[3].ends_with("word (4/4)"));
+        assert!(segments.parts.iter().all(|part| part.len() <= 160));
+    }
+
+    #[test]
+    fn concatenated() {
+        assert_eq!(count(&"a".repeat(161)), 2);
+        assert_eq!(count(&"a".repeat(306)), 2);
+        assert_eq!(count(&"ê".repeat(71)), 2);
+        assert_eq!(count(&"ü".repeat(71)), 1);
+    }
+}
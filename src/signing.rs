@@ -0,0 +1,23 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs the DID with the server key, the result is URL safe.
+pub fn sign_did(secret: &str, did: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(did.as_bytes());
+    base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks the signature in constant time.
+pub fn verify_did(secret: &str, did: &str, signature: &str) -> bool {
+    let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(did.as_bytes());
+    mac.verify(&signature).is_ok()
+}
@@ -0,0 +1,773 @@
+use super::{
+    MessageLog, MessageSchedule, NotifyLog, PushToken, ScheduledMessage, SendLog, SendRecord,
+    TokenStore, SEND_RETENTION_HOURS,
+};
+use crate::acrobits::AcrobitsSMS;
+use crate::errors::VoipBitsError;
+use crate::provider::Sent;
+use crate::search;
+use anyhow::Error;
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{model::AttributeValue, Client, SdkError};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use fehler::throws;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::{BTreeSet, HashMap};
+use tracing::{info, warn};
+
+/// Each DID is one item. The devices live in the `devices` map attribute, keyed by the push token.
+///
+/// Older versions kept `appid\token\selector` strings in the `tokens` string set. Such items
+/// are rewritten into `devices` the first time they are read.
+///
+/// The time of the last push from each sender is kept in an item of its own, under
+/// `<did>#notified#<sender>`, as `notified_at`. It expires through the table's time to live
+/// on `expires_at`, like the sends.
+///
+/// The sends by idempotency key are items of their own, under `<did>#send#<key>`, with
+/// `started_at` and, once sent, `ids` and `segments`. They expire through the table's
+/// time to live on `expires_at`.
+///
+/// The message log lives in its own table, with `did` as the partition key and
+/// `sk` (`<sending date>#<sms id>`) as the sort key. Each message id is claimed by an item
+/// under `<did>#id#<sms id>`, so that a message archived again under another date is not
+/// saved twice. For searching, each word of a message also has an item under
+/// `<did>#word#<word>` with the same sort key.
+///
+/// The scheduled messages also live in their own table, with `did` as the partition key
+/// and `id` as the sort key. The scheduler scans it for the due ones.
+pub struct DynamoDBStore {
+    client: Client,
+    table: String,
+    messages_table: String,
+    schedule_table: String,
+}
+
+impl DynamoDBStore {
+    pub async fn new(table: &str, messages_table: &str, schedule_table: &str) -> DynamoDBStore {
+        let shared_config = aws_config::load_from_env().await;
+        let client = Client::new(&shared_config);
+        DynamoDBStore {
+            client,
+            table: table.into(),
+            messages_table: messages_table.into(),
+            schedule_table: schedule_table.into(),
+        }
+    }
+
+    /// Returns the devices of the DID, and whether the item still has legacy tokens.
+    #[throws(Error)]
+    async fn load(&self, did: &str) -> (HashMap<String, PushToken>, bool) {
+        let resp = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key("did", AttributeValue::S(did.into()))
+            .send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        let mut record = match resp.item {
+            Some(record) => record,
+            None => return (HashMap::new(), false),
+        };
+
+        let mut devices = HashMap::new();
+        if let Some(AttributeValue::M(saved)) = record.remove("devices") {
+            for (push_token, device) in saved {
+                match from_attribute(&device) {
+                    Some(device) => {
+                        devices.insert(push_token, device);
+                    }
+                    None => warn!("Skipping malformed device record for {}", did),
+                }
+            }
+        }
+
+        let legacy = match record.remove("tokens") {
+            Some(AttributeValue::Ss(tokens)) => tokens,
+            _ => vec![],
+        };
+        for token in &legacy {
+            match PushToken::from_legacy(token) {
+                Some(device) if !devices.contains_key(&device.push_token) => {
+                    devices.insert(device.push_token.clone(), device);
+                }
+                Some(_) => {}
+                None => warn!("Skipping malformed legacy token record for {}", did),
+            }
+        }
+
+        (devices, !legacy.is_empty())
+    }
+
+    /// Replaces the whole device map of the DID, dropping the legacy tokens.
+    #[throws(Error)]
+    async fn rewrite(&self, did: &str, devices: &HashMap<String, PushToken>) {
+        info!("[storage] Rewriting all the devices of {}", did);
+
+        let devices = devices
+            .iter()
+            .map(|(push_token, device)| (push_token.clone(), to_attribute(device)))
+            .collect();
+
+        self.client
+            .update_item()
+            .table_name(&self.table)
+            .key("did", AttributeValue::S(did.into()))
+            .update_expression("SET devices = :devices REMOVE tokens")
+            .expression_attribute_values(":devices", AttributeValue::M(devices))
+            .send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+    }
+}
+
+#[async_trait]
+impl TokenStore for DynamoDBStore {
+    async fn save_token(&self, did: &str, token: &PushToken) -> Result<(), Error> {
+        let (mut devices, legacy) = self.load(did).await?;
+        let token = token.merge_into(devices.get(&token.push_token));
+
+        if legacy || devices.is_empty() {
+            // The map attribute has to exist before a single entry can be set.
+            devices.insert(token.push_token.clone(), token);
+            return self.rewrite(did, &devices).await;
+        }
+
+        self.client
+            .update_item()
+            .table_name(&self.table)
+            .key("did", AttributeValue::S(did.into()))
+            .update_expression("SET devices.#token = :device")
+            .expression_attribute_names("#token", &token.push_token)
+            .expression_attribute_values(":device", to_attribute(&token))
+            .send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn get_tokens(&self, did: &str) -> Result<Vec<PushToken>, Error> {
+        let (devices, legacy) = self.load(did).await?;
+        if legacy {
+            self.rewrite(did, &devices).await?;
+        }
+
+        if devices.is_empty() {
+            return Err(VoipBitsError::NoPushTokenAvailable(did.into()).into());
+        }
+
+        Ok(devices.into_values().collect())
+    }
+
+    async fn remove_tokens(&self, did: &str, tokens: &[PushToken]) -> Result<(), Error> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        let paths: Vec<_> = (0..tokens.len())
+            .map(|i| format!("devices.#token{}", i))
+            .collect();
+        let mut req = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .key("did", AttributeValue::S(did.into()))
+            .update_expression(format!("REMOVE {}", paths.join(", ")));
+        for (i, token) in tokens.iter().enumerate() {
+            req = req.expression_attribute_names(format!("#token{}", i), &token.push_token);
+        }
+
+        req.send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageLog for DynamoDBStore {
+    async fn append_message(&self, did: &str, sms: &AcrobitsSMS) -> Result<(), Error> {
+        // The messages are keyed by date, and `/send` archives a message a moment before the
+        // carrier dates it, so the id is claimed first for the message to be saved once
+        let claim = self
+            .client
+            .put_item()
+            .table_name(&self.messages_table)
+            .item("did", AttributeValue::S(message_id_key(did, &sms.sms_id)))
+            .item("sk", AttributeValue::S(MESSAGE_ID_SK.into()))
+            .condition_expression("attribute_not_exists(did)")
+            .send()
+            .await;
+        match claim {
+            Ok(_) => {}
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(VoipBitsError::Storage(e.to_string()).into()),
+        }
+
+        if let Err(e) = self.put_message(did, sms).await {
+            // Saved by the next attempt then
+            let release = self
+                .client
+                .delete_item()
+                .table_name(&self.messages_table)
+                .key("did", AttributeValue::S(message_id_key(did, &sms.sms_id)))
+                .key("sk", AttributeValue::S(MESSAGE_ID_SK.into()))
+                .send()
+                .await;
+            if let Err(release) = release {
+                warn!("Cannot release the id of {}: {}", sms.sms_id, release);
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    async fn list_messages(
+        &self,
+        did: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        let mut messages = vec![];
+        let mut start_key = None;
+
+        loop {
+            let resp = self
+                .client
+                .query()
+                .table_name(&self.messages_table)
+                .key_condition_expression("did = :did AND sk >= :since")
+                .expression_attribute_values(":did", AttributeValue::S(did.into()))
+                .expression_attribute_values(":since", AttributeValue::S(sort_date(since)))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+            for item in resp.items.unwrap_or_default() {
+                match message_from_item(&item) {
+                    Some(sms) => messages.push(sms),
+                    None => warn!("Skipping malformed message record for {}", did),
+                }
+            }
+
+            start_key = resp.last_evaluated_key;
+            if start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(messages)
+    }
+
+    async fn search_messages(
+        &self,
+        did: &str,
+        words: &[String],
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        contact: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        // With a single word and no other filter, the newest hits are all it takes
+        let most = match (words, contact) {
+            ([_], None) => Some(limit),
+            _ => None,
+        };
+
+        // The sort keys of the messages that have every word so far
+        let mut hits: Option<BTreeSet<String>> = None;
+        for word in words {
+            let keys = self.word_hits(did, word, since, until, most).await?;
+            hits = Some(match hits {
+                Some(hits) => hits.intersection(&keys).cloned().collect(),
+                None => keys,
+            });
+        }
+
+        // Newest first, a few at a time, until there are enough
+        stream::iter(hits.unwrap_or_default().into_iter().rev())
+            .map(|sk| {
+                self.client
+                    .get_item()
+                    .table_name(&self.messages_table)
+                    .key("did", AttributeValue::S(did.into()))
+                    .key("sk", AttributeValue::S(sk))
+                    .send()
+            })
+            .buffered(CONCURRENCY)
+            .map_err(|e| Error::from(VoipBitsError::Storage(e.to_string())))
+            .try_filter_map(|resp| async move {
+                Ok(resp
+                    .item
+                    .as_ref()
+                    .and_then(message_from_item)
+                    .filter(|sms| match contact {
+                        Some(contact) => search::with_contact(sms, contact),
+                        None => true,
+                    }))
+            })
+            .take(limit)
+            .try_collect()
+            .await
+    }
+}
+
+impl DynamoDBStore {
+    /// Saves the message under its date, along with its word index items.
+    #[throws(Error)]
+    async fn put_message(&self, did: &str, sms: &AcrobitsSMS) {
+        let sk = format!("{}#{}", sort_date(sms.sending_date), sms.sms_id);
+        let mut req = self
+            .client
+            .put_item()
+            .table_name(&self.messages_table)
+            .item("did", AttributeValue::S(did.into()))
+            .item("sk", AttributeValue::S(sk.clone()))
+            .item("sms_id", AttributeValue::S(sms.sms_id.clone()))
+            .item(
+                "sending_date",
+                AttributeValue::S(sms.sending_date.to_rfc3339()),
+            )
+            .item("sms_text", AttributeValue::S(sms.sms_text.clone()))
+            .item(
+                "attachments",
+                AttributeValue::S(serde_json::to_string(&sms.attachments)?),
+            );
+        if let Some(ref sender) = sms.sender {
+            req = req.item("sender", AttributeValue::S(sender.clone()));
+        }
+        if let Some(ref recipient) = sms.recipient {
+            req = req.item("recipient", AttributeValue::S(recipient.clone()));
+        }
+
+        req.send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        stream::iter(search::words(&sms.sms_text))
+            .map(|word| {
+                self.client
+                    .put_item()
+                    .table_name(&self.messages_table)
+                    .item("did", AttributeValue::S(word_key(did, &word)))
+                    .item("sk", AttributeValue::S(sk.clone()))
+                    .send()
+            })
+            .buffer_unordered(CONCURRENCY)
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))
+            .try_collect::<Vec<_>>()
+            .await?;
+    }
+
+    /// The sort keys of the messages of the DID that have `word`, sent between `since`
+    /// and `until`. Only the `most` newest of them if given.
+    #[throws(Error)]
+    async fn word_hits(
+        &self,
+        did: &str,
+        word: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        most: Option<usize>,
+    ) -> BTreeSet<String> {
+        let mut keys = BTreeSet::new();
+        let mut start_key = None;
+
+        loop {
+            let resp = self
+                .client
+                .query()
+                .table_name(&self.messages_table)
+                .key_condition_expression("did = :word AND sk BETWEEN :since AND :until")
+                .expression_attribute_values(":word", AttributeValue::S(word_key(did, word)))
+                .expression_attribute_values(":since", AttributeValue::S(sort_date(since)))
+                // Past the `#<sms id>` of the messages sent at `until`
+                .expression_attribute_values(
+                    ":until",
+                    AttributeValue::S(format!("{}~", sort_date(until))),
+                )
+                .scan_index_forward(false)
+                .set_limit(most.map(|most| (most - keys.len()).min(i32::MAX as usize) as i32))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+            for item in resp.items.unwrap_or_default() {
+                if let Some(sk) = item.get("sk").and_then(|v| v.as_s().ok()) {
+                    keys.insert(sk.clone());
+                }
+            }
+
+            start_key = resp.last_evaluated_key;
+            if start_key.is_none() || matches!(most, Some(most) if keys.len() >= most) {
+                break;
+            }
+        }
+
+        keys
+    }
+}
+
+/// How many requests a search or the word index of a message makes at once
+const CONCURRENCY: usize = 8;
+
+/// The sort key of the item claiming a message id.
+const MESSAGE_ID_SK: &str = "id";
+
+/// The partition of the item claiming the id of a message, which is saved once.
+fn message_id_key(did: &str, sms_id: &str) -> String {
+    format!("{}#id#{}", did, sms_id)
+}
+
+/// The partition of the messages that have `word`.
+fn word_key(did: &str, word: &str) -> String {
+    format!("{}#word#{}", did, word)
+}
+
+#[async_trait]
+impl NotifyLog for DynamoDBStore {
+    async fn claim_notification(
+        &self,
+        did: &str,
+        sender: &str,
+        now: DateTime<Utc>,
+        since: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        // Kept for as long as it holds back the pushes
+        let expires_at = now + (now - since);
+
+        let resp = self
+            .client
+            .put_item()
+            .table_name(&self.table)
+            .item("did", AttributeValue::S(notified_key(did, sender)))
+            .item("notified_at", AttributeValue::S(sort_date(now)))
+            .item(
+                "expires_at",
+                AttributeValue::N(expires_at.timestamp().to_string()),
+            )
+            .condition_expression("attribute_not_exists(did) OR notified_at <= :since")
+            .expression_attribute_values(":since", AttributeValue::S(sort_date(since)))
+            .send()
+            .await;
+
+        match resp {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(VoipBitsError::Storage(e.to_string()).into()),
+        }
+    }
+}
+
+#[async_trait]
+impl SendLog for DynamoDBStore {
+    async fn claim_send(
+        &self,
+        did: &str,
+        key: &str,
+        now: DateTime<Utc>,
+        stale: DateTime<Utc>,
+    ) -> Result<Option<SendRecord>, Error> {
+        let item_key = send_key(did, key);
+        let expires_at = now + Duration::hours(SEND_RETENTION_HOURS);
+
+        let resp = self
+            .client
+            .put_item()
+            .table_name(&self.table)
+            .item("did", AttributeValue::S(item_key.clone()))
+            .item("started_at", AttributeValue::S(sort_date(now)))
+            .item(
+                "expires_at",
+                AttributeValue::N(expires_at.timestamp().to_string()),
+            )
+            // Expired items linger until DynamoDB gets to deleting them
+            .condition_expression(
+                "attribute_not_exists(did) OR expires_at < :now \
+                 OR (attribute_not_exists(ids) AND started_at <= :stale)",
+            )
+            .expression_attribute_values(":now", AttributeValue::N(now.timestamp().to_string()))
+            .expression_attribute_values(":stale", AttributeValue::S(sort_date(stale)))
+            .send()
+            .await;
+
+        match resp {
+            Ok(_) => return Ok(None),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() => {}
+            Err(e) => return Err(VoipBitsError::Storage(e.to_string()).into()),
+        }
+
+        let resp = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key("did", AttributeValue::S(item_key))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        let item = resp.item.unwrap_or_default();
+        let ids = match item.get("ids").and_then(|v| v.as_l().ok()) {
+            Some(ids) => ids,
+            None => return Ok(Some(SendRecord::Pending)),
+        };
+        let segments = item
+            .get("segments")
+            .and_then(|v| v.as_n().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(ids.len());
+
+        Ok(Some(SendRecord::Sent(Sent {
+            ids: ids
+                .iter()
+                .filter_map(|id| id.as_s().ok().cloned())
+                .collect(),
+            segments,
+        })))
+    }
+
+    async fn complete_send(&self, did: &str, key: &str, sent: &Sent) -> Result<(), Error> {
+        let ids = sent.ids.iter().cloned().map(AttributeValue::S).collect();
+
+        self.client
+            .update_item()
+            .table_name(&self.table)
+            .key("did", AttributeValue::S(send_key(did, key)))
+            .update_expression("SET ids = :ids, segments = :segments")
+            .expression_attribute_values(":ids", AttributeValue::L(ids))
+            .expression_attribute_values(":segments", AttributeValue::N(sent.segments.to_string()))
+            .send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn release_send(&self, did: &str, key: &str) -> Result<(), Error> {
+        self.client
+            .delete_item()
+            .table_name(&self.table)
+            .key("did", AttributeValue::S(send_key(did, key)))
+            .send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageSchedule for DynamoDBStore {
+    async fn schedule_message(&self, message: &ScheduledMessage) -> Result<(), Error> {
+        self.client
+            .put_item()
+            .table_name(&self.schedule_table)
+            .item("did", AttributeValue::S(message.did.clone()))
+            .item("id", AttributeValue::S(message.id.clone()))
+            .item("cred", AttributeValue::S(message.cred.clone()))
+            .item("recipient", AttributeValue::S(message.to.clone()))
+            .item("sms_text", AttributeValue::S(message.text.clone()))
+            .item(
+                "media",
+                AttributeValue::S(serde_json::to_string(&message.media)?),
+            )
+            .item("send_at", AttributeValue::S(sort_date(message.send_at)))
+            .send()
+            .await
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn list_scheduled(&self, did: &str) -> Result<Vec<ScheduledMessage>, Error> {
+        let mut messages = vec![];
+        let mut start_key = None;
+
+        loop {
+            let resp = self
+                .client
+                .query()
+                .table_name(&self.schedule_table)
+                .key_condition_expression("did = :did")
+                .expression_attribute_values(":did", AttributeValue::S(did.into()))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+            messages.extend(scheduled_from_items(resp.items.unwrap_or_default()));
+
+            start_key = resp.last_evaluated_key;
+            if start_key.is_none() {
+                break;
+            }
+        }
+
+        messages.sort_by_key(|message| message.send_at);
+        Ok(messages)
+    }
+
+    async fn due_messages(&self, now: DateTime<Utc>) -> Result<Vec<ScheduledMessage>, Error> {
+        let mut messages = vec![];
+        let mut start_key = None;
+
+        loop {
+            let resp = self
+                .client
+                .scan()
+                .table_name(&self.schedule_table)
+                .filter_expression("send_at <= :now")
+                .expression_attribute_values(":now", AttributeValue::S(sort_date(now)))
+                .set_exclusive_start_key(start_key)
+                .send()
+                .await
+                .map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+            messages.extend(scheduled_from_items(resp.items.unwrap_or_default()));
+
+            start_key = resp.last_evaluated_key;
+            if start_key.is_none() {
+                break;
+            }
+        }
+
+        messages.sort_by_key(|message| message.send_at);
+        Ok(messages)
+    }
+
+    async fn take_scheduled(&self, did: &str, id: &str) -> Result<bool, Error> {
+        let resp = self
+            .client
+            .delete_item()
+            .table_name(&self.schedule_table)
+            .key("did", AttributeValue::S(did.into()))
+            .key("id", AttributeValue::S(id.into()))
+            .condition_expression("attribute_exists(id)")
+            .send()
+            .await;
+
+        match resp {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(VoipBitsError::Storage(e.to_string()).into()),
+        }
+    }
+}
+
+fn scheduled_from_items(items: Vec<HashMap<String, AttributeValue>>) -> Vec<ScheduledMessage> {
+    items
+        .iter()
+        .filter_map(|item| {
+            let message = scheduled_from_item(item);
+            if message.is_none() {
+                warn!("Skipping malformed scheduled message record");
+            }
+            message
+        })
+        .collect()
+}
+
+fn scheduled_from_item(item: &HashMap<String, AttributeValue>) -> Option<ScheduledMessage> {
+    let string = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+
+    Some(ScheduledMessage {
+        id: string("id")?,
+        did: string("did")?,
+        cred: string("cred")?,
+        to: string("recipient")?,
+        text: string("sms_text")?,
+        media: serde_json::from_str(&string("media")?).ok()?,
+        send_at: DateTime::parse_from_rfc3339(&string("send_at")?)
+            .ok()?
+            .with_timezone(&Utc),
+    })
+}
+
+fn notified_key(did: &str, sender: &str) -> String {
+    format!("{}#notified#{}", did, sender)
+}
+
+fn send_key(did: &str, key: &str) -> String {
+    format!("{}#send#{}", did, key)
+}
+
+/// Fixed width so that the sort keys order by date.
+fn sort_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn message_from_item(item: &HashMap<String, AttributeValue>) -> Option<AcrobitsSMS> {
+    let string = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+
+    Some(AcrobitsSMS {
+        sms_id: string("sms_id")?,
+        sending_date: DateTime::parse_from_rfc3339(&string("sending_date")?)
+            .ok()?
+            .with_timezone(&Utc),
+        sender: string("sender"),
+        recipient: string("recipient"),
+        sms_text: string("sms_text")?,
+        attachments: serde_json::from_str(&string("attachments")?).ok()?,
+    })
+}
+
+fn to_attribute(token: &PushToken) -> AttributeValue {
+    let mut attrs = HashMap::new();
+    attrs.insert("appid".into(), AttributeValue::S(token.appid.clone()));
+    attrs.insert(
+        "push_token".into(),
+        AttributeValue::S(token.push_token.clone()),
+    );
+    attrs.insert("selector".into(), AttributeValue::S(token.selector.clone()));
+    attrs.insert(
+        "first_seen".into(),
+        AttributeValue::S(token.first_seen.to_rfc3339()),
+    );
+    attrs.insert(
+        "last_seen".into(),
+        AttributeValue::S(token.last_seen.to_rfc3339()),
+    );
+    if let Some(ref user_agent) = token.user_agent {
+        attrs.insert("user_agent".into(), AttributeValue::S(user_agent.clone()));
+    }
+    AttributeValue::M(attrs)
+}
+
+fn from_attribute(attr: &AttributeValue) -> Option<PushToken> {
+    let attrs = attr.as_m().ok()?;
+    let string = |name: &str| attrs.get(name).and_then(|v| v.as_s().ok()).cloned();
+    let date = |name: &str| {
+        string(name)
+            .and_then(|v| DateTime::parse_from_rfc3339(&v).ok())
+            .map(|v| v.with_timezone(&Utc))
+    };
+
+    Some(PushToken {
+        appid: string("appid")?,
+        push_token: string("push_token")?,
+        selector: string("selector")?,
+        first_seen: date("first_seen")?,
+        last_seen: date("last_seen")?,
+        user_agent: string("user_agent"),
+    })
+}
@@ -0,0 +1,229 @@
+use super::{
+    send_expiry, MessageLog, MessageSchedule, NotifyLog, PushToken, ScheduledMessage, SendLog,
+    SendRecord, TokenStore,
+};
+use crate::acrobits::AcrobitsSMS;
+use crate::errors::VoipBitsError;
+use crate::provider::Sent;
+use crate::search;
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The start of a send, and its outcome once sent
+type SendEntry = (DateTime<Utc>, Option<Sent>);
+
+/// Keeps everything in the process memory. Nothing survives a restart,
+/// so this is only meant for tests and local development.
+#[derive(Default)]
+pub struct MemoryStore {
+    /// did -> push token -> device
+    tokens: Mutex<HashMap<String, HashMap<String, PushToken>>>,
+    /// did -> messages, oldest first
+    messages: Mutex<HashMap<String, Vec<AcrobitsSMS>>>,
+    /// (did, sender) -> last push
+    notifications: Mutex<HashMap<(String, String), DateTime<Utc>>>,
+    /// (did, idempotency key) -> send
+    sends: Mutex<HashMap<(String, String), SendEntry>>,
+    /// Messages waiting for their time, in no particular order
+    scheduled: Mutex<Vec<ScheduledMessage>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryStore {
+    async fn save_token(&self, did: &str, token: &PushToken) -> Result<(), Error> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let devices = tokens.entry(did.into()).or_default();
+        let token = token.merge_into(devices.get(&token.push_token));
+        devices.insert(token.push_token.clone(), token);
+
+        Ok(())
+    }
+
+    async fn get_tokens(&self, did: &str) -> Result<Vec<PushToken>, Error> {
+        let tokens = self.tokens.lock().unwrap();
+        match tokens.get(did) {
+            Some(devices) if !devices.is_empty() => Ok(devices.values().cloned().collect()),
+            _ => Err(VoipBitsError::NoPushTokenAvailable(did.into()).into()),
+        }
+    }
+
+    async fn remove_tokens(&self, did: &str, tokens: &[PushToken]) -> Result<(), Error> {
+        if let Some(devices) = self.tokens.lock().unwrap().get_mut(did) {
+            for token in tokens {
+                devices.remove(&token.push_token);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageLog for MemoryStore {
+    async fn append_message(&self, did: &str, sms: &AcrobitsSMS) -> Result<(), Error> {
+        let mut messages = self.messages.lock().unwrap();
+        let log = messages.entry(did.into()).or_default();
+        if !log.iter().any(|saved| saved.sms_id == sms.sms_id) {
+            log.push(sms.clone());
+            log.sort_by_key(|sms| sms.sending_date);
+        }
+
+        Ok(())
+    }
+
+    async fn list_messages(
+        &self,
+        did: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .get(did)
+            .map(|log| {
+                log.iter()
+                    .filter(|sms| sms.sending_date >= since)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn search_messages(
+        &self,
+        did: &str,
+        words: &[String],
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        contact: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .get(did)
+            .map(|log| {
+                log.iter()
+                    .rev()
+                    .filter(|sms| sms.sending_date >= since && sms.sending_date <= until)
+                    .filter(|sms| search::has_words(&sms.sms_text, words))
+                    .filter(|sms| match contact {
+                        Some(contact) => search::with_contact(sms, contact),
+                        None => true,
+                    })
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl NotifyLog for MemoryStore {
+    async fn claim_notification(
+        &self,
+        did: &str,
+        sender: &str,
+        now: DateTime<Utc>,
+        since: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let mut notifications = self.notifications.lock().unwrap();
+        let key = (did.to_string(), sender.to_string());
+        if matches!(notifications.get(&key), Some(last) if *last > since) {
+            return Ok(false);
+        }
+
+        notifications.insert(key, now);
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl SendLog for MemoryStore {
+    async fn claim_send(
+        &self,
+        did: &str,
+        key: &str,
+        now: DateTime<Utc>,
+        stale: DateTime<Utc>,
+    ) -> Result<Option<SendRecord>, Error> {
+        let mut sends = self.sends.lock().unwrap();
+        let expiry = send_expiry(now);
+        sends.retain(|_, (started, _)| *started >= expiry);
+
+        let key = (did.to_string(), key.to_string());
+        match sends.get(&key) {
+            Some((_, Some(sent))) => return Ok(Some(SendRecord::Sent(sent.clone()))),
+            Some((started, None)) if *started > stale => return Ok(Some(SendRecord::Pending)),
+            _ => {}
+        }
+
+        sends.insert(key, (now, None));
+        Ok(None)
+    }
+
+    async fn complete_send(&self, did: &str, key: &str, sent: &Sent) -> Result<(), Error> {
+        let mut sends = self.sends.lock().unwrap();
+        if let Some((_, outcome)) = sends.get_mut(&(did.to_string(), key.to_string())) {
+            *outcome = Some(sent.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn release_send(&self, did: &str, key: &str) -> Result<(), Error> {
+        let mut sends = self.sends.lock().unwrap();
+        sends.remove(&(did.to_string(), key.to_string()));
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageSchedule for MemoryStore {
+    async fn schedule_message(&self, message: &ScheduledMessage) -> Result<(), Error> {
+        self.scheduled.lock().unwrap().push(message.clone());
+
+        Ok(())
+    }
+
+    async fn list_scheduled(&self, did: &str) -> Result<Vec<ScheduledMessage>, Error> {
+        let scheduled = self.scheduled.lock().unwrap();
+        let mut messages: Vec<_> = scheduled
+            .iter()
+            .filter(|message| message.did == did)
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| message.send_at);
+
+        Ok(messages)
+    }
+
+    async fn due_messages(&self, now: DateTime<Utc>) -> Result<Vec<ScheduledMessage>, Error> {
+        let scheduled = self.scheduled.lock().unwrap();
+        let mut messages: Vec<_> = scheduled
+            .iter()
+            .filter(|message| message.send_at <= now)
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| message.send_at);
+
+        Ok(messages)
+    }
+
+    async fn take_scheduled(&self, did: &str, id: &str) -> Result<bool, Error> {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        let before = scheduled.len();
+        scheduled.retain(|message| !(message.did == did && message.id == id));
+
+        Ok(scheduled.len() < before)
+    }
+}
@@ -0,0 +1,344 @@
+mod dynamodb;
+mod memory;
+mod sqlite;
+
+pub use self::dynamodb::DynamoDBStore;
+pub use self::memory::MemoryStore;
+pub use self::sqlite::SqliteStore;
+
+use crate::acrobits::AcrobitsSMS;
+use crate::provider::Sent;
+use crate::Opt;
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use fehler::throws;
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::Arc};
+use tracing::info;
+
+/// A device registered through `/report`, identified by its push token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushToken {
+    pub appid: String,
+    pub push_token: String,
+    pub selector: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// The User-Agent of the softphone, which carries the app name and version
+    pub user_agent: Option<String>,
+}
+
+impl PushToken {
+    pub fn new(appid: &str, push_token: &str, selector: &str, user_agent: Option<&str>) -> Self {
+        let now = Utc::now();
+        PushToken {
+            appid: appid.into(),
+            push_token: push_token.into(),
+            selector: selector.into(),
+            first_seen: now,
+            last_seen: now,
+            user_agent: user_agent.map(Into::into),
+        }
+    }
+
+    /// Parses the `appid\token\selector` records written by older versions.
+    /// The legacy format has no timestamps so they are set to now.
+    pub fn from_legacy(record: &str) -> Option<Self> {
+        match record.split('\\').collect::<Vec<_>>().as_slice() {
+            [appid, push_token, selector] => Some(Self::new(appid, push_token, selector, None)),
+            _ => None,
+        }
+    }
+
+    /// Keeps `first_seen` from the already saved record of the same device.
+    pub fn merge_into(&self, saved: Option<&PushToken>) -> PushToken {
+        let mut token = self.clone();
+        if let Some(saved) = saved {
+            token.first_seen = saved.first_seen.min(self.first_seen);
+        }
+        token
+    }
+}
+
+/// A message queued for later through `/schedule` or an `@at` prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: String,
+    pub did: String,
+    /// The encrypted credential it was queued with, which the scheduler sends it with
+    pub cred: String,
+    pub to: String,
+    pub text: String,
+    pub media: Vec<String>,
+    pub send_at: DateTime<Utc>,
+}
+
+impl ScheduledMessage {
+    pub fn new(
+        did: &str,
+        cred: &str,
+        to: &str,
+        text: &str,
+        media: &[String],
+        send_at: DateTime<Utc>,
+    ) -> ScheduledMessage {
+        ScheduledMessage {
+            id: format!("{:016x}", rand::random::<u64>()),
+            did: did.into(),
+            cred: cred.into(),
+            to: to.into(),
+            text: text.into(),
+            media: media.to_vec(),
+            send_at,
+        }
+    }
+}
+
+/// How long a send is remembered under its idempotency key
+const SEND_RETENTION_HOURS: i64 = 24;
+
+/// Before this, a send is forgotten.
+fn send_expiry(now: DateTime<Utc>) -> DateTime<Utc> {
+    now - Duration::hours(SEND_RETENTION_HOURS)
+}
+
+/// What became of the send claimed under an idempotency key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendRecord {
+    /// The request that claimed it is still sending
+    Pending,
+    Sent(Sent),
+}
+
+/// The backend picked at startup, shared by all the handlers.
+pub type Storage = Arc<dyn Store>;
+
+/// Everything a storage backend keeps.
+pub trait Store: TokenStore + MessageLog + NotifyLog + SendLog + MessageSchedule {}
+
+impl<T: TokenStore + MessageLog + NotifyLog + SendLog + MessageSchedule> Store for T {}
+
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    /// Inserts the device, or refreshes it if the push token is already known.
+    async fn save_token(&self, did: &str, token: &PushToken) -> Result<(), Error>;
+
+    /// Fails with `VoipBitsError::NoPushTokenAvailable` if no device is registered for the DID.
+    async fn get_tokens(&self, did: &str) -> Result<Vec<PushToken>, Error>;
+
+    async fn remove_tokens(&self, did: &str, tokens: &[PushToken]) -> Result<(), Error>;
+}
+
+/// The messages of the carriers that cannot list them afterwards, see `telnyx::Telnyx`,
+/// and the archive, see `archive`.
+#[async_trait]
+pub trait MessageLog: Send + Sync {
+    /// Saving a message already in the log is a no-op.
+    async fn append_message(&self, did: &str, sms: &AcrobitsSMS) -> Result<(), Error>;
+
+    /// The messages of the DID sent at or after `since`, oldest first.
+    async fn list_messages(
+        &self,
+        did: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<AcrobitsSMS>, Error>;
+
+    /// The messages of the DID sent between `since` and `until` that have every one of
+    /// `words`, as given by `search::words`, newest first. Only those with `contact` if
+    /// given, and at most `limit` of them.
+    async fn search_messages(
+        &self,
+        did: &str,
+        words: &[String],
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        contact: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<AcrobitsSMS>, Error>;
+}
+
+/// When the devices of a DID were last pushed a message from each sender, so that
+/// `/notify` pushes once for a burst of messages.
+#[async_trait]
+pub trait NotifyLog: Send + Sync {
+    /// Records a push from `sender` at `now`, unless the last one is after `since`.
+    /// Returns whether the push is to be sent.
+    async fn claim_notification(
+        &self,
+        did: &str,
+        sender: &str,
+        now: DateTime<Utc>,
+        since: DateTime<Utc>,
+    ) -> Result<bool, Error>;
+}
+
+/// The sends of each DID by idempotency key, so that a softphone retrying `/send`
+/// gets the ids of the first attempt instead of texting the contact twice.
+#[async_trait]
+pub trait SendLog: Send + Sync {
+    /// Claims `key` for a send starting at `now`. Returns `None` when the claim is ours,
+    /// or what the earlier send under `key` came to. A pending claim made before `stale`
+    /// is taken over, the request that made it is presumed dead.
+    async fn claim_send(
+        &self,
+        did: &str,
+        key: &str,
+        now: DateTime<Utc>,
+        stale: DateTime<Utc>,
+    ) -> Result<Option<SendRecord>, Error>;
+
+    /// Records the outcome of the send claimed under `key`.
+    async fn complete_send(&self, did: &str, key: &str, sent: &Sent) -> Result<(), Error>;
+
+    /// Gives up the claim after a failed send, so that a retry sends again.
+    async fn release_send(&self, did: &str, key: &str) -> Result<(), Error>;
+}
+
+/// The messages waiting for their time, see `schedule`.
+#[async_trait]
+pub trait MessageSchedule: Send + Sync {
+    async fn schedule_message(&self, message: &ScheduledMessage) -> Result<(), Error>;
+
+    /// The messages of the DID still to be sent, soonest first.
+    async fn list_scheduled(&self, did: &str) -> Result<Vec<ScheduledMessage>, Error>;
+
+    /// The messages of every DID due at `now`, soonest first.
+    async fn due_messages(&self, now: DateTime<Utc>) -> Result<Vec<ScheduledMessage>, Error>;
+
+    /// Removes the message and returns whether it was still there. The scheduler only
+    /// sends the messages it takes, so that overlapping runs send each once.
+    async fn take_scheduled(&self, did: &str, id: &str) -> Result<bool, Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    DynamoDB,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dynamodb" => Ok(StorageBackend::DynamoDB),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!(
+                "unknown storage backend {}, expecting one of dynamodb, sqlite or memory",
+                s
+            )),
+        }
+    }
+}
+
+#[throws(Error)]
+pub async fn connect(opt: &Opt) -> Storage {
+    info!("Using {:?} storage backend", opt.storage);
+
+    let storage: Storage = match opt.storage {
+        StorageBackend::DynamoDB => Arc::new(
+            DynamoDBStore::new(
+                &opt.dynamodb_table,
+                &opt.dynamodb_messages_table,
+                &opt.dynamodb_schedule_table,
+            )
+            .await,
+        ),
+        StorageBackend::Sqlite => Arc::new(SqliteStore::open(&opt.sqlite_path)?),
+        StorageBackend::Memory => Arc::new(MemoryStore::new()),
+    };
+    storage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    async fn archived_twice(storage: Storage) {
+        let sent = AcrobitsSMS {
+            sms_id: "SM1".into(),
+            sending_date: Utc.timestamp_opt(1_650_000_000, 0).unwrap(),
+            sender: None,
+            recipient: Some("5145550000".into()),
+            sms_text: "on my way".into(),
+            attachments: vec![],
+        };
+        // As the carrier dated it afterwards
+        let mut fetched = sent.clone();
+        fetched.sending_date += Duration::seconds(2);
+
+        storage.append_message("5145551234", &sent).await.unwrap();
+        storage
+            .append_message("5145551234", &fetched)
+            .await
+            .unwrap();
+
+        let since = Utc.timestamp_opt(0, 0).unwrap();
+        let messages = storage.list_messages("5145551234", since).await.unwrap();
+        assert_eq!(messages.len(), 1);
+
+        let words = vec!["way".to_string()];
+        let found = storage
+            .search_messages("5145551234", &words, since, Utc::now(), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    async fn searched(storage: Storage) {
+        let since = Utc.timestamp_opt(0, 0).unwrap();
+        for i in 0..6 {
+            let sms = AcrobitsSMS {
+                sms_id: format!("SM{}", i),
+                sending_date: Utc.timestamp_opt(1_650_000_000 + i, 0).unwrap(),
+                sender: Some(format!("+1 514 555 000{}", i % 2)),
+                recipient: None,
+                sms_text: format!("see you at {}", i),
+                attachments: vec![],
+            };
+            storage.append_message("5145551234", &sms).await.unwrap();
+        }
+
+        let search = |contact, limit| {
+            let storage = storage.clone();
+            async move {
+                let words = vec!["see".to_string(), "you".to_string()];
+                let found = storage
+                    .search_messages("5145551234", &words, since, Utc::now(), contact, limit)
+                    .await
+                    .unwrap();
+                found.into_iter().map(|sms| sms.sms_id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(search(None, 2).await, vec!["SM5", "SM4"]);
+        assert_eq!(search(Some("5145550001"), 2).await, vec!["SM5", "SM3"]);
+        assert_eq!(
+            search(Some("5145550000"), 10).await,
+            vec!["SM4", "SM2", "SM0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_searches() {
+        searched(Arc::new(MemoryStore::new())).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_searches() {
+        searched(Arc::new(SqliteStore::open(":memory:").unwrap())).await;
+    }
+
+    #[tokio::test]
+    async fn memory_saves_a_message_once() {
+        archived_twice(Arc::new(MemoryStore::new())).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_saves_a_message_once() {
+        archived_twice(Arc::new(SqliteStore::open(":memory:").unwrap())).await;
+    }
+}
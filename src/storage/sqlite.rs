@@ -0,0 +1,545 @@
+use super::{
+    send_expiry, MessageLog, MessageSchedule, NotifyLog, PushToken, ScheduledMessage, SendLog,
+    SendRecord, TokenStore,
+};
+use crate::acrobits::AcrobitsSMS;
+use crate::errors::VoipBitsError;
+use crate::provider::Sent;
+use crate::search;
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use fehler::throws;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+/// An embedded store for self-hosted deployments.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    #[throws(Error)]
+    pub fn open(path: &str) -> SqliteStore {
+        let conn = Connection::open(path).map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+        migrate(&conn).map_err(|e| VoipBitsError::Storage(e.to_string()))?;
+
+        SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool so that sqlite doesn't stall the runtime.
+    #[throws(Error)]
+    async fn with_conn<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await?
+            .map_err(|e| VoipBitsError::Storage(e.to_string()))?
+    }
+}
+
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS devices (
+            did TEXT NOT NULL,
+            push_token TEXT NOT NULL,
+            appid TEXT NOT NULL,
+            selector TEXT NOT NULL,
+            first_seen TEXT NOT NULL,
+            last_seen TEXT NOT NULL,
+            user_agent TEXT,
+            PRIMARY KEY (did, push_token)
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            did TEXT NOT NULL,
+            sms_id TEXT NOT NULL,
+            sending_date TEXT NOT NULL,
+            sender TEXT,
+            recipient TEXT,
+            sms_text TEXT NOT NULL,
+            attachments TEXT NOT NULL,
+            PRIMARY KEY (did, sms_id)
+        );
+        CREATE INDEX IF NOT EXISTS messages_by_date ON messages (did, sending_date);
+        CREATE TABLE IF NOT EXISTS notifications (
+            did TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            notified_at TEXT NOT NULL,
+            PRIMARY KEY (did, sender)
+        );
+        CREATE TABLE IF NOT EXISTS sends (
+            did TEXT NOT NULL,
+            idempotency_key TEXT NOT NULL,
+            started_at TEXT NOT NULL,
+            ids TEXT,
+            segments INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (did, idempotency_key)
+        );
+        CREATE TABLE IF NOT EXISTS scheduled (
+            did TEXT NOT NULL,
+            id TEXT NOT NULL,
+            cred TEXT NOT NULL,
+            recipient TEXT NOT NULL,
+            sms_text TEXT NOT NULL,
+            media TEXT NOT NULL,
+            send_at TEXT NOT NULL,
+            PRIMARY KEY (did, id)
+        );
+        CREATE INDEX IF NOT EXISTS scheduled_by_date ON scheduled (send_at);",
+    )?;
+
+    // The words of the message log, kept up to date by the trigger. The messages from
+    // before the index are indexed when it is created.
+    let indexed: Option<String> = conn
+        .query_row(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if indexed.is_none() {
+        info!("[storage] Indexing the message log");
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE messages_fts USING fts5(
+                sms_text,
+                content = 'messages',
+                tokenize = 'unicode61 remove_diacritics 0'
+            );
+            CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, sms_text) VALUES (new.rowid, new.sms_text);
+            END;
+            INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');",
+        )?;
+    }
+
+    // The first version only kept (appid, push_token, selector) in `push_tokens`.
+    let legacy: Option<String> = conn
+        .query_row(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'push_tokens'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if legacy.is_some() {
+        info!("[storage] Migrating the legacy push_tokens table");
+        let now = Utc::now();
+        conn.execute(
+            "INSERT OR IGNORE INTO devices (did, push_token, appid, selector, first_seen, last_seen)
+             SELECT did, push_token, appid, selector, ?1, ?1 FROM push_tokens",
+            params![now],
+        )?;
+        conn.execute_batch("DROP TABLE push_tokens;")?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl TokenStore for SqliteStore {
+    async fn save_token(&self, did: &str, token: &PushToken) -> Result<(), Error> {
+        let did = did.to_string();
+        let token = token.clone();
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO devices
+                    (did, push_token, appid, selector, first_seen, last_seen, user_agent)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (did, push_token) DO UPDATE SET
+                    appid = excluded.appid,
+                    selector = excluded.selector,
+                    first_seen = min(first_seen, excluded.first_seen),
+                    last_seen = excluded.last_seen,
+                    user_agent = excluded.user_agent",
+                params![
+                    did,
+                    token.push_token,
+                    token.appid,
+                    token.selector,
+                    token.first_seen,
+                    token.last_seen,
+                    token.user_agent
+                ],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_tokens(&self, did: &str) -> Result<Vec<PushToken>, Error> {
+        let owned_did = did.to_string();
+
+        let tokens = self
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT appid, push_token, selector, first_seen, last_seen, user_agent
+                     FROM devices WHERE did = ?1",
+                )?;
+                let rows = stmt.query_map(params![owned_did], |row| {
+                    Ok(PushToken {
+                        appid: row.get(0)?,
+                        push_token: row.get(1)?,
+                        selector: row.get(2)?,
+                        first_seen: row.get(3)?,
+                        last_seen: row.get(4)?,
+                        user_agent: row.get(5)?,
+                    })
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        if tokens.is_empty() {
+            return Err(VoipBitsError::NoPushTokenAvailable(did.into()).into());
+        }
+
+        Ok(tokens)
+    }
+
+    async fn remove_tokens(&self, did: &str, tokens: &[PushToken]) -> Result<(), Error> {
+        let did = did.to_string();
+        let push_tokens: Vec<_> = tokens.iter().map(|t| t.push_token.clone()).collect();
+
+        self.with_conn(move |conn| {
+            let mut stmt =
+                conn.prepare("DELETE FROM devices WHERE did = ?1 AND push_token = ?2")?;
+            for push_token in push_tokens {
+                stmt.execute(params![did, push_token])?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageLog for SqliteStore {
+    async fn append_message(&self, did: &str, sms: &AcrobitsSMS) -> Result<(), Error> {
+        let did = did.to_string();
+        let sms = sms.clone();
+        let attachments = serde_json::to_string(&sms.attachments)?;
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO messages
+                    (did, sms_id, sending_date, sender, recipient, sms_text, attachments)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    did,
+                    sms.sms_id,
+                    sms.sending_date,
+                    sms.sender,
+                    sms.recipient,
+                    sms.sms_text,
+                    attachments
+                ],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_messages(
+        &self,
+        did: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        let did = did.to_string();
+
+        let rows = self
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT sms_id, sending_date, sender, recipient, sms_text, attachments
+                     FROM messages WHERE did = ?1 AND sending_date >= ?2
+                     ORDER BY sending_date",
+                )?;
+                let rows = stmt.query_map(params![did, since], message_row)?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        with_attachments(rows)
+    }
+
+    async fn search_messages(
+        &self,
+        did: &str,
+        words: &[String],
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        contact: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        let did = did.to_string();
+        let contact = contact.map(str::to_string);
+        // Quoted, the words are taken as is rather than as FTS5 query syntax
+        let query = words
+            .iter()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let rows = self
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT m.sms_id, m.sending_date, m.sender, m.recipient, m.sms_text,
+                        m.attachments
+                     FROM messages_fts JOIN messages m ON m.rowid = messages_fts.rowid
+                     WHERE messages_fts MATCH ?1 AND m.did = ?2
+                        AND m.sending_date >= ?3 AND m.sending_date <= ?4
+                     ORDER BY m.sending_date DESC",
+                )?;
+                let rows = stmt.query_map(params![query, did, since, until], message_row)?;
+                // The contacts match whatever the formatting of the numbers, so not in SQL
+                rows.filter(|row| match (row, &contact) {
+                    (Ok((sms, _)), Some(contact)) => search::with_contact(sms, contact),
+                    _ => true,
+                })
+                .take(limit)
+                .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        with_attachments(rows)
+    }
+}
+
+/// A message, with its attachments still in JSON.
+fn message_row(row: &Row) -> rusqlite::Result<(AcrobitsSMS, String)> {
+    let attachments: String = row.get(5)?;
+    Ok((
+        AcrobitsSMS {
+            sms_id: row.get(0)?,
+            sending_date: row.get(1)?,
+            sender: row.get(2)?,
+            recipient: row.get(3)?,
+            sms_text: row.get(4)?,
+            attachments: vec![],
+        },
+        attachments,
+    ))
+}
+
+fn with_attachments(rows: Vec<(AcrobitsSMS, String)>) -> Result<Vec<AcrobitsSMS>, Error> {
+    rows.into_iter()
+        .map(|(mut sms, attachments)| {
+            sms.attachments = serde_json::from_str(&attachments)?;
+            Ok(sms)
+        })
+        .collect()
+}
+
+#[async_trait]
+impl NotifyLog for SqliteStore {
+    async fn claim_notification(
+        &self,
+        did: &str,
+        sender: &str,
+        now: DateTime<Utc>,
+        since: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let did = did.to_string();
+        let sender = sender.to_string();
+
+        // The upsert changes no row when the last push is after `since`
+        let changed = self
+            .with_conn(move |conn| {
+                conn.execute(
+                    "INSERT INTO notifications (did, sender, notified_at) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (did, sender) DO UPDATE SET notified_at = excluded.notified_at
+                     WHERE notified_at <= ?4",
+                    params![did, sender, now, since],
+                )
+            })
+            .await?;
+
+        Ok(changed > 0)
+    }
+}
+
+#[async_trait]
+impl SendLog for SqliteStore {
+    async fn claim_send(
+        &self,
+        did: &str,
+        key: &str,
+        now: DateTime<Utc>,
+        stale: DateTime<Utc>,
+    ) -> Result<Option<SendRecord>, Error> {
+        let did = did.to_string();
+        let key = key.to_string();
+
+        // Nothing else uses the connection in between, so the claim and the read are atomic
+        let existing = self
+            .with_conn(move |conn| {
+                conn.execute(
+                    "DELETE FROM sends WHERE started_at < ?1",
+                    params![send_expiry(now)],
+                )?;
+                let changed = conn.execute(
+                    "INSERT INTO sends (did, idempotency_key, started_at) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (did, idempotency_key) DO UPDATE SET
+                        started_at = excluded.started_at
+                     WHERE ids IS NULL AND started_at <= ?4",
+                    params![did, key, now, stale],
+                )?;
+                if changed > 0 {
+                    return Ok(None);
+                }
+
+                conn.query_row(
+                    "SELECT ids, segments FROM sends WHERE did = ?1 AND idempotency_key = ?2",
+                    params![did, key],
+                    |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()
+            })
+            .await?;
+
+        Ok(match existing {
+            None => None,
+            Some((None, _)) => Some(SendRecord::Pending),
+            Some((Some(ids), segments)) => Some(SendRecord::Sent(Sent {
+                ids: serde_json::from_str(&ids)?,
+                segments: segments as usize,
+            })),
+        })
+    }
+
+    async fn complete_send(&self, did: &str, key: &str, sent: &Sent) -> Result<(), Error> {
+        let did = did.to_string();
+        let key = key.to_string();
+        let ids = serde_json::to_string(&sent.ids)?;
+        let segments = sent.segments as i64;
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE sends SET ids = ?3, segments = ?4
+                 WHERE did = ?1 AND idempotency_key = ?2",
+                params![did, key, ids, segments],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn release_send(&self, did: &str, key: &str) -> Result<(), Error> {
+        let did = did.to_string();
+        let key = key.to_string();
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM sends WHERE did = ?1 AND idempotency_key = ?2",
+                params![did, key],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageSchedule for SqliteStore {
+    async fn schedule_message(&self, message: &ScheduledMessage) -> Result<(), Error> {
+        let message = message.clone();
+        let media = serde_json::to_string(&message.media)?;
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO scheduled (did, id, cred, recipient, sms_text, media, send_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    message.did,
+                    message.id,
+                    message.cred,
+                    message.to,
+                    message.text,
+                    media,
+                    message.send_at
+                ],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_scheduled(&self, did: &str) -> Result<Vec<ScheduledMessage>, Error> {
+        let did = did.to_string();
+        self.select_scheduled("did = ?1", did).await
+    }
+
+    async fn due_messages(&self, now: DateTime<Utc>) -> Result<Vec<ScheduledMessage>, Error> {
+        self.select_scheduled("send_at <= ?1", now).await
+    }
+
+    async fn take_scheduled(&self, did: &str, id: &str) -> Result<bool, Error> {
+        let did = did.to_string();
+        let id = id.to_string();
+
+        let deleted = self
+            .with_conn(move |conn| {
+                conn.execute(
+                    "DELETE FROM scheduled WHERE did = ?1 AND id = ?2",
+                    params![did, id],
+                )
+            })
+            .await?;
+
+        Ok(deleted > 0)
+    }
+}
+
+impl SqliteStore {
+    /// The scheduled messages matching `condition` on `param`, soonest first.
+    #[throws(Error)]
+    async fn select_scheduled<P>(&self, condition: &'static str, param: P) -> Vec<ScheduledMessage>
+    where
+        P: rusqlite::ToSql + Send + 'static,
+    {
+        let rows = self
+            .with_conn(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT did, id, cred, recipient, sms_text, media, send_at
+                     FROM scheduled WHERE {} ORDER BY send_at",
+                    condition
+                ))?;
+                let rows = stmt.query_map(params![param], |row| {
+                    let media: String = row.get(5)?;
+                    Ok((
+                        ScheduledMessage {
+                            did: row.get(0)?,
+                            id: row.get(1)?,
+                            cred: row.get(2)?,
+                            to: row.get(3)?,
+                            text: row.get(4)?,
+                            media: vec![],
+                            send_at: row.get(6)?,
+                        },
+                        media,
+                    ))
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|(mut message, media)| {
+                message.media = serde_json::from_str(&media)?;
+                Ok(message)
+            })
+            .collect::<Result<_, Error>>()?
+    }
+}
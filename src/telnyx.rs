@@ -0,0 +1,555 @@
+use crate::acrobits::{AcrobitsSMS, Attachment};
+use crate::errors::VoipBitsError;
+use crate::logging;
+use crate::phone::{Country, PhoneNumber};
+use crate::provider::{Sent, SmsProvider};
+use crate::segment;
+use crate::storage::Storage;
+use crate::voipms::guess_content_type;
+use crate::Opt;
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use fehler::{throw, throws};
+use reqwest::{Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use std::convert::TryFrom;
+use tracing::{error, info};
+
+pub const TELNYX_API_URL: &str = "https://api.telnyx.com";
+
+/// How old a webhook can be before it is taken as a replay.
+const WEBHOOK_TOLERANCE_SECS: i64 = 300;
+
+/// Telnyx has no way to list the past messages of a number, so the messages are
+/// kept in the storage message log as they are sent and received.
+pub struct Telnyx {
+    api_key: String,
+    messaging_profile_id: String,
+    /// Digits only, with the country code
+    pub did: String,
+    storage: Storage,
+    base_url: String,
+    /// The country of national destination numbers
+    country: Country,
+    client: Client,
+}
+
+impl Telnyx {
+    pub fn new(
+        api_key: &str,
+        messaging_profile_id: &str,
+        did: &str,
+        storage: Storage,
+        base_url: &str,
+        country: Country,
+    ) -> Telnyx {
+        Telnyx {
+            api_key: api_key.into(),
+            messaging_profile_id: messaging_profile_id.into(),
+            did: did.trim_start_matches('+').into(),
+            storage,
+            base_url: base_url.trim_end_matches('/').into(),
+            country,
+            client: Client::new(),
+        }
+    }
+
+    #[throws(Error)]
+    async fn request<O>(&self, req: RequestBuilder) -> O
+    where
+        O: DeserializeOwned,
+    {
+        let resp = req.bearer_auth(&self.api_key).send().await?;
+        let status = resp.status();
+        let payload = resp.text().await?;
+
+        if !status.is_success() {
+            error!("Telnyx response: ({}) {}", status, payload);
+            let message = serde_json::from_str::<TelnyxErrors>(&payload)
+                .ok()
+                .and_then(|e| e.errors.into_iter().next())
+                .map(|e| e.detail.unwrap_or(e.title))
+                .unwrap_or_else(|| format!("HTTP {}", status));
+            throw!(VoipBitsError::Upstream(message));
+        }
+
+        info!("Telnyx response: ({}) {} bytes", status, payload.len());
+        if logging::sensitive() {
+            info!("Telnyx response payload: {}", payload);
+        }
+
+        serde_json::from_str(&payload)
+            .map_err(|e| VoipBitsError::Upstream(format!("unexpected response: {}", e)))?
+    }
+
+    /// Points the webhook of the messaging profile at `webhook_url`. The profile holds
+    /// other numbers too, so only once the DID is confirmed to be one of them.
+    #[throws(Error)]
+    async fn set_webhook(&self, webhook_url: &str) {
+        self.check_number().await?;
+
+        let url = format!(
+            "{}/v2/messaging_profiles/{}",
+            self.base_url, self.messaging_profile_id
+        );
+        let _: serde_json::Value = self
+            .request(
+                self.client
+                    .patch(&url)
+                    .json(&json!({ "webhook_url": webhook_url })),
+            )
+            .await?;
+    }
+
+    /// Fails unless the DID is on the account and sends through the messaging profile.
+    #[throws(Error)]
+    async fn check_number(&self) {
+        let numbers: TelnyxResponse<Vec<TelnyxNumber>> = self
+            .request(
+                self.client
+                    .get(format!("{}/v2/phone_numbers", self.base_url))
+                    .query(&[("filter[phone_number]", format!("+{}", self.did))]),
+            )
+            .await?;
+
+        let on_profile = numbers.data.iter().any(|number| {
+            number.phone_number.trim_start_matches('+') == self.did
+                && number.messaging_profile_id.as_deref() == Some(&self.messaging_profile_id)
+        });
+        if !on_profile {
+            throw!(VoipBitsError::Upstream(format!(
+                "{} is not on this Telnyx messaging profile",
+                self.did
+            )));
+        }
+    }
+}
+
+/// Checks the Ed25519 signature Telnyx puts on its webhooks, made over `<timestamp>|<body>`
+/// with the account key (Mission Control → Keys & Credentials → Public Key).
+#[throws(VoipBitsError)]
+pub fn verify_webhook(
+    public_key: &str,
+    signature: &str,
+    timestamp: &str,
+    body: &[u8],
+    now: DateTime<Utc>,
+) {
+    let public_key = base64::decode(public_key)
+        .ok()
+        .and_then(|key| PublicKey::from_bytes(&key).ok())
+        .ok_or_else(|| VoipBitsError::Internal("invalid Telnyx public key".into()))?;
+
+    let invalid = || VoipBitsError::InvalidSignature("telnyx webhook".into());
+    let signature = base64::decode(signature)
+        .ok()
+        .and_then(|sig| Signature::try_from(sig.as_slice()).ok())
+        .ok_or_else(invalid)?;
+
+    let sent_at = timestamp
+        .parse::<i64>()
+        .ok()
+        .and_then(|ts| Utc.timestamp_opt(ts, 0).single())
+        .ok_or_else(invalid)?;
+    if (now - sent_at).num_seconds().abs() > WEBHOOK_TOLERANCE_SECS {
+        throw!(invalid());
+    }
+
+    let mut signed = format!("{}|", timestamp).into_bytes();
+    signed.extend_from_slice(body);
+    public_key
+        .verify(&signed, &signature)
+        .map_err(|_| invalid())?;
+}
+
+/// Turns a `message.received` webhook into an Acrobits message, returning it with the DID
+/// it was sent to. Other events give `None`.
+#[throws(VoipBitsError)]
+pub fn inbound_message(body: &[u8]) -> Option<(String, AcrobitsSMS)> {
+    let webhook: TelnyxWebhook = serde_json::from_slice(body)
+        .map_err(|e| VoipBitsError::Validation(format!("invalid webhook: {}", e)))?;
+    if webhook.data.event_type != "message.received" {
+        return None;
+    }
+
+    let msg = webhook.data.payload;
+    let did = msg
+        .to
+        .first()
+        .map(|to| to.phone_number.trim_start_matches('+').to_string())
+        .ok_or_else(|| VoipBitsError::Validation("message has no recipient".into()))?;
+
+    let sms = AcrobitsSMS {
+        sms_id: msg.id,
+        sending_date: msg
+            .received_at
+            .unwrap_or(webhook.data.occurred_at)
+            .with_timezone(&Utc),
+        sender: Some(msg.from.phone_number),
+        recipient: None,
+        sms_text: msg.text.unwrap_or_default(),
+        attachments: msg
+            .media
+            .into_iter()
+            .map(|media| Attachment {
+                content_type: media
+                    .content_type
+                    .unwrap_or_else(|| "application/octet-stream".into()),
+                content_url: media.url,
+                content_size: media.size,
+                filename: None,
+            })
+            .collect(),
+    };
+
+    Some((did, sms))
+}
+
+#[async_trait]
+impl SmsProvider for Telnyx {
+    fn did(&self) -> &str {
+        &self.did
+    }
+
+    async fn send(&self, dst: &str, msg: &str, media: &[String]) -> Result<Sent, Error> {
+        let dst = PhoneNumber::parse(dst, self.country)?.to_string();
+        let msg = msg.trim();
+        if msg.is_empty() && media.is_empty() {
+            return Err(VoipBitsError::EmptyMessage.into());
+        }
+
+        let mut body = json!({
+            "from": format!("+{}", self.did),
+            "to": dst,
+            "text": msg,
+        });
+        if !media.is_empty() {
+            body["media_urls"] = json!(media);
+        }
+
+        info!("Sending message with {} media", media.len());
+        let resp: TelnyxResponse<TelnyxSentMessage> = self
+            .request(
+                self.client
+                    .post(format!("{}/v2/messages", self.base_url))
+                    .json(&body),
+            )
+            .await?;
+
+        let sms = AcrobitsSMS {
+            sms_id: resp.data.id.clone(),
+            sending_date: Utc::now(),
+            sender: None,
+            recipient: Some(dst),
+            sms_text: msg.into(),
+            attachments: media
+                .iter()
+                .map(|url| Attachment {
+                    content_type: guess_content_type(url).into(),
+                    content_url: url.clone(),
+                    content_size: None,
+                    filename: None,
+                })
+                .collect(),
+        };
+        self.storage.append_message(&self.did, &sms).await?;
+
+        Ok(Sent {
+            ids: vec![resp.data.id],
+            segments: resp.data.parts.unwrap_or_else(|| segment::count(msg)),
+        })
+    }
+
+    async fn fetch_after_id(&self, last_id: &str) -> Result<Vec<AcrobitsSMS>, Error> {
+        let smss = self.fetch_from_date(None).await?;
+        match smss.iter().position(|sms| sms.sms_id == last_id) {
+            Some(pos) => Ok(smss[pos + 1..].to_vec()),
+            None => {
+                // Older than the log, or from before the number moved here
+                info!("{} is not in the message log, syncing it all", last_id);
+                Ok(smss)
+            }
+        }
+    }
+
+    async fn fetch_from_date(
+        &self,
+        from: Option<DateTime<Utc>>,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        // The log is only keyed by the DID, which the credential alone does not vouch for
+        self.check_number().await?;
+
+        let from = from.unwrap_or_else(|| Utc::now() - Duration::days(90));
+        self.storage.list_messages(&self.did, from).await
+    }
+
+    async fn verify(&self) -> Result<(), Error> {
+        self.check_number().await
+    }
+
+    async fn register_callback(&self, opt: &Opt) -> Result<(), Error> {
+        self.set_webhook(&opt.telnyx_inbound_url()).await
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxError {
+    title: String,
+    detail: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxErrors {
+    errors: Vec<TelnyxError>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxResponse<T> {
+    data: T,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxSentMessage {
+    id: String,
+    /// The number of SMS the text was sent as
+    parts: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxNumber {
+    phone_number: String,
+    messaging_profile_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxWebhook {
+    data: TelnyxEvent,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxEvent {
+    event_type: String,
+    occurred_at: DateTime<Utc>,
+    payload: TelnyxMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxPhoneNumber {
+    phone_number: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxMedia {
+    url: String,
+    content_type: Option<String>,
+    size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelnyxMessage {
+    id: String,
+    from: TelnyxPhoneNumber,
+    to: Vec<TelnyxPhoneNumber>,
+    text: Option<String>,
+    #[serde(default)]
+    media: Vec<TelnyxMedia>,
+    received_at: Option<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::Query,
+        routing::{get, patch},
+        Json, Router,
+    };
+    use ed25519_dalek::{ExpandedSecretKey, SecretKey};
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    const WEBHOOK: &str = r#"{
+        "data": {
+            "event_type": "message.received",
+            "id": "b301ed3f-1490-491f-995f-6e64e69674d4",
+            "occurred_at": "2022-03-01T10:00:00.000+00:00",
+            "payload": {
+                "id": "84cca175-9755-4859-b67f-4730d7f58aa3",
+                "direction": "inbound",
+                "from": {"phone_number": "+15145550000", "carrier": "Telnyx"},
+                "to": [{"phone_number": "+15145551111", "status": "webhook_delivered"}],
+                "text": "hello",
+                "media": [{"url": "https://example.com/a.jpg", "content_type": "image/jpeg", "size": 1024}],
+                "received_at": "2022-03-01T09:59:59.000+00:00"
+            },
+            "record_type": "event"
+        }
+    }"#;
+
+    fn sign(timestamp: &str, body: &[u8]) -> (String, String) {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let mut signed = format!("{}|", timestamp).into_bytes();
+        signed.extend_from_slice(body);
+        let signature = ExpandedSecretKey::from(&secret).sign(&signed, &public);
+
+        (
+            base64::encode(public.as_bytes()),
+            base64::encode(signature.to_bytes()),
+        )
+    }
+
+    #[test]
+    fn webhook_signature() {
+        let now = Utc.timestamp_opt(1646128800, 0).unwrap();
+        let body = WEBHOOK.as_bytes();
+        let (public_key, signature) = sign("1646128800", body);
+
+        assert!(verify_webhook(&public_key, &signature, "1646128800", body, now).is_ok());
+        // Tampered body
+        assert!(verify_webhook(&public_key, &signature, "1646128800", b"{}", now).is_err());
+        // Tampered timestamp
+        assert!(verify_webhook(&public_key, &signature, "1646128801", body, now).is_err());
+        // Replayed later
+        let later = now + Duration::minutes(10);
+        assert!(verify_webhook(&public_key, &signature, "1646128800", body, later).is_err());
+    }
+
+    #[test]
+    fn inbound() {
+        let (did, sms) = inbound_message(WEBHOOK.as_bytes()).unwrap().unwrap();
+
+        assert_eq!(did, "15145551111");
+        assert_eq!(sms.sms_id, "84cca175-9755-4859-b67f-4730d7f58aa3");
+        assert_eq!(sms.sender.as_deref(), Some("+15145550000"));
+        assert_eq!(sms.sending_date.to_rfc3339(), "2022-03-01T09:59:59+00:00");
+        assert_eq!(sms.attachments[0].content_size, Some(1024));
+
+        let sent = WEBHOOK.replace("message.received", "message.sent");
+        assert!(inbound_message(sent.as_bytes()).unwrap().is_none());
+    }
+
+    type Webhooks = Arc<Mutex<Vec<String>>>;
+
+    /// Serves a Telnyx account with 15145551111 on `profile` and 15145552222 on another
+    /// profile, returning its base URL and the webhook URLs set on `profile`.
+    async fn mock_telnyx() -> (String, Webhooks) {
+        let webhooks = Webhooks::default();
+        let set = webhooks.clone();
+        let app = Router::new()
+            .route(
+                "/v2/phone_numbers",
+                get(|query: Query<HashMap<String, String>>| async move {
+                    let phone_number = &query["filter[phone_number]"];
+                    let number = |profile| {
+                        json!([{ "phone_number": phone_number, "messaging_profile_id": profile }])
+                    };
+                    let data = match phone_number.as_str() {
+                        "+15145551111" => number("profile"),
+                        "+15145552222" => number("other"),
+                        _ => json!([]),
+                    };
+                    Json(json!({ "data": data }))
+                }),
+            )
+            .route(
+                "/v2/messaging_profiles/profile",
+                patch(move |Json(body): Json<Value>| {
+                    set.lock()
+                        .unwrap()
+                        .push(body["webhook_url"].as_str().unwrap().into());
+                    async { Json(json!({ "data": { "id": "profile" } })) }
+                }),
+            );
+
+        let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (format!("http://{}", addr), webhooks)
+    }
+
+    fn telnyx(did: &str, storage: Storage, base_url: &str) -> Telnyx {
+        Telnyx::new(
+            "key",
+            "profile",
+            did,
+            storage,
+            base_url,
+            "US".parse().unwrap(),
+        )
+    }
+
+    fn is_upstream(err: &Error) -> bool {
+        matches!(
+            err.downcast_ref::<VoipBitsError>(),
+            Some(VoipBitsError::Upstream(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn fetch_after_unknown_id() {
+        let storage: Storage = Arc::new(crate::storage::MemoryStore::new());
+        let (did, sms) = inbound_message(WEBHOOK.as_bytes()).unwrap().unwrap();
+        let mut recent = sms.clone();
+        recent.sending_date = Utc::now() - Duration::hours(1);
+        storage.append_message(&did, &recent).await.unwrap();
+
+        let (base_url, _) = mock_telnyx().await;
+        let telnyx = telnyx(&did, storage, &base_url);
+        assert!(telnyx.fetch_after_id(&sms.sms_id).await.unwrap().is_empty());
+        let smss = telnyx.fetch_after_id("gone").await.unwrap();
+        assert_eq!(smss.len(), 1);
+        assert_eq!(smss[0].sms_id, sms.sms_id);
+    }
+
+    #[tokio::test]
+    async fn foreign_number() {
+        let storage: Storage = Arc::new(crate::storage::MemoryStore::new());
+        let (_, sms) = inbound_message(WEBHOOK.as_bytes()).unwrap().unwrap();
+        let (base_url, webhooks) = mock_telnyx().await;
+
+        // Not on the account, then on another messaging profile
+        for did in &["15145559999", "15145552222"] {
+            storage.append_message(did, &sms).await.unwrap();
+            let telnyx = telnyx(did, storage.clone(), &base_url);
+
+            assert!(is_upstream(
+                &telnyx.fetch_from_date(None).await.unwrap_err()
+            ));
+            assert!(is_upstream(
+                &telnyx.fetch_after_id("gone").await.unwrap_err()
+            ));
+            assert!(is_upstream(&telnyx.verify().await.unwrap_err()));
+            let err = telnyx
+                .set_webhook("https://example.com/telnyx/inbound")
+                .await;
+            assert!(is_upstream(&err.unwrap_err()));
+        }
+        assert!(webhooks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn webhook() {
+        let storage: Storage = Arc::new(crate::storage::MemoryStore::new());
+        let (base_url, webhooks) = mock_telnyx().await;
+
+        let telnyx = telnyx("15145551111", storage, &base_url);
+        telnyx
+            .set_webhook("https://example.com/telnyx/inbound")
+            .await
+            .unwrap();
+        assert_eq!(
+            *webhooks.lock().unwrap(),
+            vec!["https://example.com/telnyx/inbound"]
+        );
+    }
+}
@@ -0,0 +1,640 @@
+use crate::acrobits::{AcrobitsSMS, Attachment};
+use crate::errors::VoipBitsError;
+use crate::logging;
+use crate::phone::{Country, PhoneNumber};
+use crate::provider::{Sent, SmsProvider};
+use crate::segment;
+use crate::Opt;
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use fehler::{throw, throws};
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::{Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha1::Sha1;
+use tracing::{error, info};
+
+pub const TWILIO_API_URL: &str = "https://api.twilio.com";
+
+pub struct Twilio {
+    account_sid: String,
+    auth_token: String,
+    /// Digits only, with the country code
+    pub did: String,
+    /// The encrypted credential, embedded into the webhook URL so that inbound
+    /// messages can be authenticated with the auth token.
+    cred: String,
+    base_url: String,
+    /// The country of national destination numbers
+    country: Country,
+    client: Client,
+}
+
+impl Twilio {
+    pub fn new(
+        account_sid: &str,
+        auth_token: &str,
+        did: &str,
+        cred: &str,
+        base_url: &str,
+        country: Country,
+    ) -> Twilio {
+        Twilio {
+            account_sid: account_sid.into(),
+            auth_token: auth_token.into(),
+            did: did.trim_start_matches('+').into(),
+            cred: cred.into(),
+            base_url: base_url.trim_end_matches('/').into(),
+            country,
+            client: Client::new(),
+        }
+    }
+
+    fn account_url(&self, path: &str) -> String {
+        format!(
+            "{}/2010-04-01/Accounts/{}/{}",
+            self.base_url, self.account_sid, path
+        )
+    }
+
+    fn e164(&self) -> String {
+        format!("+{}", self.did)
+    }
+
+    #[throws(Error)]
+    async fn request<O>(&self, req: RequestBuilder) -> O
+    where
+        O: DeserializeOwned,
+    {
+        let resp = req
+            .basic_auth(&self.account_sid, Some(&self.auth_token))
+            .send()
+            .await?;
+        let status = resp.status();
+        let payload = resp.text().await?;
+
+        if !status.is_success() {
+            error!("Twilio response: ({}) {}", status, payload);
+            let error = serde_json::from_str::<TwilioError>(&payload).ok();
+            if error.as_ref().and_then(|e| e.code) == Some(NOT_FOUND) {
+                throw!(NotFound);
+            }
+            let message = error
+                .map(|e| e.message)
+                .unwrap_or_else(|| format!("HTTP {}", status));
+            throw!(VoipBitsError::Upstream(message));
+        }
+
+        info!("Twilio response: ({}) {} bytes", status, payload.len());
+        if logging::sensitive() {
+            info!("Twilio response payload: {}", payload);
+        }
+
+        serde_json::from_str(&payload)
+            .map_err(|e| VoipBitsError::Upstream(format!("unexpected response: {}", e)))?
+    }
+
+    /// The DID as set up on the account, failing when it is not on the account.
+    #[throws(Error)]
+    async fn incoming_number(&self) -> TwilioNumber {
+        let numbers: TwilioNumberList = self
+            .request(
+                self.client
+                    .get(self.account_url("IncomingPhoneNumbers.json"))
+                    .query(&[("PhoneNumber", self.e164())]),
+            )
+            .await?;
+        numbers
+            .incoming_phone_numbers
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                VoipBitsError::Upstream(format!("{} is not on this Twilio account", self.did))
+            })?
+    }
+
+    /// Lists the messages matching `filter`, following the pagination.
+    #[throws(Error)]
+    async fn list_messages(&self, filter: (&str, &str), from: DateTime<Utc>) -> Vec<TwilioMessage> {
+        let date = from.format("%Y-%m-%d").to_string();
+        let mut page: TwilioMessagePage = self
+            .request(self.client.get(self.account_url("Messages.json")).query(&[
+                filter,
+                ("DateSent>", &date),
+                ("PageSize", "1000"),
+            ]))
+            .await?;
+
+        let mut messages = vec![];
+        loop {
+            messages.append(&mut page.messages);
+            match page.next_page_uri {
+                Some(ref uri) => {
+                    let url = format!("{}{}", self.base_url, uri);
+                    page = self.request(self.client.get(&url)).await?;
+                }
+                None => break,
+            }
+        }
+        messages
+    }
+
+    #[throws(Error)]
+    async fn to_acrobits(&self, msg: &TwilioMessage) -> AcrobitsSMS {
+        let sending_date = msg.date_sent.as_ref().unwrap_or(&msg.date_created);
+        let sending_date = DateTime::parse_from_rfc2822(sending_date)
+            .map_err(|e| VoipBitsError::Upstream(format!("invalid date: {}", e)))?
+            .with_timezone(&Utc);
+
+        let mut sms = AcrobitsSMS {
+            sms_id: msg.sid.clone(),
+            sending_date,
+            sender: None,
+            recipient: None,
+            sms_text: msg.body.clone().unwrap_or_default(),
+            attachments: vec![],
+        };
+        if msg.direction == "inbound" {
+            sms.sender = Some(msg.from.clone());
+        } else {
+            sms.recipient = Some(msg.to.clone());
+        }
+
+        if msg.num_media.as_deref().unwrap_or("0") != "0" {
+            let url = self.account_url(&format!("Messages/{}/Media.json", msg.sid));
+            let media: TwilioMediaList = self.request(self.client.get(&url)).await?;
+            sms.attachments = media
+                .media_list
+                .into_iter()
+                .map(|media| Attachment {
+                    content_type: media.content_type,
+                    content_url: format!(
+                        "{}{}",
+                        self.base_url,
+                        media.uri.trim_end_matches(".json")
+                    ),
+                    content_size: None,
+                    filename: None,
+                })
+                .collect();
+        }
+
+        sms
+    }
+
+    #[throws(Error)]
+    async fn fetch_messages(&self, from: DateTime<Utc>) -> Vec<AcrobitsSMS> {
+        let e164 = self.e164();
+        let mut messages = self.list_messages(("To", &e164), from).await?;
+        messages.extend(self.list_messages(("From", &e164), from).await?);
+
+        let mut smss = vec![];
+        for msg in &messages {
+            smss.push(self.to_acrobits(msg).await?);
+        }
+        smss.sort_by_key(|sms| sms.sending_date);
+        smss
+    }
+}
+
+/// Checks the `X-Twilio-Signature` of a webhook request.
+///
+/// `url` is the full URL Twilio requested, including the query string, and
+/// `params` are the form-encoded POST parameters.
+pub fn verify_signature(
+    auth_token: &str,
+    url: &str,
+    params: &[(String, String)],
+    signature: &str,
+) -> bool {
+    let signature = match base64::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut params: Vec<_> = params.iter().collect();
+    params.sort();
+
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(auth_token.as_bytes()).expect("HMAC takes any key size");
+    mac.update(url.as_bytes());
+    for (key, value) in params {
+        mac.update(key.as_bytes());
+        mac.update(value.as_bytes());
+    }
+    mac.verify(&signature).is_ok()
+}
+
+/// Turns an inbound webhook into an Acrobits message, returning it with the DID it was sent to.
+#[throws(VoipBitsError)]
+pub fn inbound_message(params: &[(String, String)]) -> (String, AcrobitsSMS) {
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    let required = |name: &str| {
+        param(name).ok_or_else(|| VoipBitsError::Validation(format!("missing {}", name)))
+    };
+
+    let num_media: usize = param("NumMedia").and_then(|n| n.parse().ok()).unwrap_or(0);
+    let attachments = (0..num_media)
+        .filter_map(|i| {
+            Some(Attachment {
+                content_type: param(&format!("MediaContentType{}", i))
+                    .unwrap_or_else(|| "application/octet-stream".into()),
+                content_url: param(&format!("MediaUrl{}", i))?,
+                content_size: None,
+                filename: None,
+            })
+        })
+        .collect();
+
+    let did = required("To")?.trim_start_matches('+').to_string();
+    let sms = AcrobitsSMS {
+        sms_id: required("MessageSid")?,
+        sending_date: Utc::now(),
+        sender: Some(required("From")?),
+        recipient: None,
+        sms_text: param("Body").unwrap_or_default(),
+        attachments,
+    };
+
+    (did, sms)
+}
+
+#[async_trait]
+impl SmsProvider for Twilio {
+    fn did(&self) -> &str {
+        &self.did
+    }
+
+    async fn send(&self, dst: &str, msg: &str, media: &[String]) -> Result<Sent, Error> {
+        let dst = PhoneNumber::parse(dst, self.country)?.to_string();
+        let msg = msg.trim();
+        if msg.is_empty() && media.is_empty() {
+            return Err(VoipBitsError::EmptyMessage.into());
+        }
+
+        let from = self.e164();
+        let mut form = vec![("To", dst.as_str()), ("From", from.as_str()), ("Body", msg)];
+        form.extend(media.iter().map(|url| ("MediaUrl", url.as_str())));
+
+        info!("Sending message with {} media", media.len());
+        let resp: TwilioMessage = self
+            .request(
+                self.client
+                    .post(self.account_url("Messages.json"))
+                    .form(&form),
+            )
+            .await?;
+
+        let segments = resp
+            .num_segments
+            .as_deref()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or_else(|| segment::count(msg));
+        Ok(Sent {
+            ids: vec![resp.sid],
+            segments,
+        })
+    }
+
+    async fn fetch_after_id(&self, last_id: &str) -> Result<Vec<AcrobitsSMS>, Error> {
+        let url = self.account_url(&format!("Messages/{}.json", last_id));
+        let last: TwilioMessage = self.request(self.client.get(&url)).await.map_err(|e| {
+            match e.downcast_ref::<NotFound>() {
+                Some(_) => VoipBitsError::NoSuchSMS(last_id.into()).into(),
+                None => e,
+            }
+        })?;
+        let last = self.to_acrobits(&last).await?;
+
+        // Message SIDs are random, so "after" can only be told by the date
+        let mut smss = self.fetch_messages(last.sending_date).await?;
+        smss.retain(|sms| sms.sending_date >= last.sending_date && sms.sms_id != last_id);
+        Ok(smss)
+    }
+
+    async fn fetch_from_date(
+        &self,
+        from: Option<DateTime<Utc>>,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        let from = from.unwrap_or_else(|| Utc::now() - Duration::days(90));
+        self.fetch_messages(from).await
+    }
+
+    async fn verify(&self) -> Result<(), Error> {
+        self.incoming_number().await.map(|_| ())
+    }
+
+    async fn register_callback(&self, opt: &Opt) -> Result<(), Error> {
+        let number = self.incoming_number().await?;
+
+        let url = self.account_url(&format!("IncomingPhoneNumbers/{}.json", number.sid));
+        let _: serde_json::Value = self
+            .request(self.client.post(&url).form(&[
+                (
+                    "SmsUrl",
+                    opt.twilio_inbound_url(&self.did, &self.cred).as_str(),
+                ),
+                ("SmsMethod", "POST"),
+            ]))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TwilioError {
+    code: Option<u32>,
+    message: String,
+}
+
+/// The error code of Twilio for a resource that does not exist
+const NOT_FOUND: u32 = 20404;
+
+/// Thrown by `Twilio::request` for `NOT_FOUND`, for callers to tell apart from failures
+/// worth retrying.
+#[derive(Debug, thiserror::Error)]
+#[error("not found on Twilio")]
+struct NotFound;
+
+#[derive(Deserialize, Debug)]
+struct TwilioMessage {
+    sid: String,
+    date_created: String,
+    date_sent: Option<String>,
+    direction: String,
+    from: String,
+    to: String,
+    body: Option<String>,
+    num_media: Option<String>,
+    num_segments: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TwilioMessagePage {
+    messages: Vec<TwilioMessage>,
+    next_page_uri: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TwilioMedia {
+    content_type: String,
+    uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TwilioMediaList {
+    media_list: Vec<TwilioMedia>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TwilioNumber {
+    sid: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TwilioNumberList {
+    incoming_phone_numbers: Vec<TwilioNumber>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::{Form, Query},
+        http::StatusCode,
+        routing::get,
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    const SID: &str = "AC00000000000000000000000000000000";
+
+    fn us() -> Country {
+        "US".parse().unwrap()
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn signature() {
+        // The example from Twilio's webhook security documentation
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+        let params = params(&[
+            ("Digits", "1234"),
+            ("To", "+18005551212"),
+            ("From", "+12349013030"),
+            ("Caller", "+12349013030"),
+            ("CallSid", "CA1234567890ABCDE"),
+        ]);
+
+        let sig = "0/KCTR6DLpKmkAf8muzZqo1nDgQ=";
+        assert!(verify_signature("12345", url, &params, sig));
+        assert!(!verify_signature("54321", url, &params, sig));
+        assert!(!verify_signature(
+            "12345",
+            "https://mycompany.com/",
+            &params,
+            sig
+        ));
+        assert!(!verify_signature("12345", url, &params, "not base64"));
+    }
+
+    #[test]
+    fn inbound() {
+        let params = params(&[
+            ("MessageSid", "SM1"),
+            ("From", "+15145550000"),
+            ("To", "+15145551111"),
+            ("Body", "hello"),
+            ("NumMedia", "1"),
+            ("MediaUrl0", "https://api.twilio.com/media/ME1"),
+            ("MediaContentType0", "image/jpeg"),
+        ]);
+        let (did, sms) = inbound_message(&params).unwrap();
+
+        assert_eq!(did, "15145551111");
+        assert_eq!(sms.sender.as_deref(), Some("+15145550000"));
+        assert_eq!(sms.sms_text, "hello");
+        assert_eq!(sms.attachments.len(), 1);
+        assert_eq!(sms.attachments[0].content_type, "image/jpeg");
+
+        assert!(inbound_message(&params[1..]).is_err());
+    }
+
+    fn message(sid: &str, direction: &str, date: &str, num_media: &str) -> Value {
+        json!({
+            "sid": sid,
+            "date_created": date,
+            "date_sent": date,
+            "direction": direction,
+            "from": if direction == "inbound" { "+15145550000" } else { "+15145551111" },
+            "to": if direction == "inbound" { "+15145551111" } else { "+15145550000" },
+            "body": format!("body of {}", sid),
+            "num_media": num_media,
+        })
+    }
+
+    /// Serves a canned Twilio account, returning its base URL.
+    async fn mock_twilio() -> String {
+        let base = format!("/2010-04-01/Accounts/{}", SID);
+        let app = Router::new()
+            .route(
+                &format!("{}/Messages.json", base),
+                get(|query: Query<HashMap<String, String>>| async move {
+                    let page = match (query.get("To"), query.get("Page").map(String::as_str)) {
+                        (Some(_), None) => json!({
+                            "messages": [message("SM2", "inbound", "Tue, 01 Mar 2022 10:00:00 +0000", "0")],
+                            "next_page_uri": format!("/2010-04-01/Accounts/{}/Messages.json?To=%2B15145551111&Page=1", SID),
+                        }),
+                        (Some(_), Some(_)) => json!({
+                            "messages": [message("SM3", "inbound", "Tue, 01 Mar 2022 12:00:00 +0000", "1")],
+                            "next_page_uri": null,
+                        }),
+                        _ => json!({
+                            "messages": [message("SM1", "outbound-api", "Tue, 01 Mar 2022 09:00:00 +0000", "0")],
+                            "next_page_uri": null,
+                        }),
+                    };
+                    Json(page)
+                })
+                .post(|Form(form): Form<Vec<(String, String)>>| async move {
+                    let get = |name: &str| form.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+                    if get("To").as_deref() != Some("+15145550000") {
+                        return Json(json!({ "code": 21211, "message": "Invalid 'To' Phone Number" }));
+                    }
+                    assert_eq!(get("From").as_deref(), Some("+15145551111"));
+                    assert_eq!(get("MediaUrl").as_deref(), Some("https://example.com/a.jpg"));
+                    Json(message("SM9", "outbound-api", "Tue, 01 Mar 2022 13:00:00 +0000", "1"))
+                }),
+            )
+            .route(
+                &format!("{}/Messages/SM404.json", base),
+                get(|| async {
+                    (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({ "code": 20404, "message": "not found", "status": 404 })),
+                    )
+                }),
+            )
+            .route(
+                &format!("{}/Messages/SM500.json", base),
+                get(|| async {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({ "code": 20500, "message": "internal error", "status": 500 })),
+                    )
+                }),
+            )
+            .route(
+                &format!("{}/IncomingPhoneNumbers.json", base),
+                get(|query: Query<HashMap<String, String>>| async move {
+                    let numbers = match query.get("PhoneNumber").map(String::as_str) {
+                        Some("+15145551111") => json!([{ "sid": "PN1" }]),
+                        _ => json!([]),
+                    };
+                    Json(json!({ "incoming_phone_numbers": numbers }))
+                }),
+            )
+            .route(
+                &format!("{}/Messages/SM3/Media.json", base),
+                get(|| async {
+                    Json(json!({
+                        "media_list": [{
+                            "content_type": "image/png",
+                            "uri": format!("/2010-04-01/Accounts/{}/Messages/SM3/Media/ME1.json", SID),
+                        }],
+                    }))
+                }),
+            );
+
+        let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn send() {
+        let base_url = mock_twilio().await;
+        let twilio = Twilio::new(SID, "token", "15145551111", "cred", &base_url, us());
+
+        let media = vec!["https://example.com/a.jpg".to_string()];
+        let sent = twilio.send("+1 514 555 0000", "hi", &media).await.unwrap();
+        assert_eq!(sent.ids, vec!["SM9"]);
+        assert_eq!(sent.segments, 1);
+
+        assert!(twilio.send("5145550000", "  ", &[]).await.is_err());
+        assert!(twilio.send("12", "hi", &[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch() {
+        let base_url = mock_twilio().await;
+        let twilio = Twilio::new(SID, "token", "15145551111", "cred", &base_url, us());
+
+        let smss = twilio.fetch_from_date(None).await.unwrap();
+        let ids: Vec<_> = smss.iter().map(|sms| sms.sms_id.as_str()).collect();
+        assert_eq!(ids, vec!["SM1", "SM2", "SM3"]);
+
+        assert_eq!(smss[0].recipient.as_deref(), Some("+15145550000"));
+        assert_eq!(smss[1].sender.as_deref(), Some("+15145550000"));
+        assert_eq!(smss[2].attachments.len(), 1);
+        assert_eq!(
+            smss[2].attachments[0].content_url,
+            format!(
+                "{}/2010-04-01/Accounts/{}/Messages/SM3/Media/ME1",
+                base_url, SID
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_after_unknown_id() {
+        let base_url = mock_twilio().await;
+        let twilio = Twilio::new(SID, "token", "15145551111", "cred", &base_url, us());
+
+        let err = twilio.fetch_after_id("SM404").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VoipBitsError>(),
+            Some(VoipBitsError::NoSuchSMS(_))
+        ));
+
+        let err = twilio.fetch_after_id("SM500").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VoipBitsError>(),
+            Some(VoipBitsError::Upstream(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        let base_url = mock_twilio().await;
+        let twilio = Twilio::new(SID, "token", "15145551111", "cred", &base_url, us());
+        assert!(twilio.verify().await.is_ok());
+
+        let elsewhere = Twilio::new(SID, "token", "15145552222", "cred", &base_url, us());
+        let err = elsewhere.verify().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VoipBitsError>(),
+            Some(VoipBitsError::Upstream(_))
+        ));
+    }
+}
@@ -1,54 +1,130 @@
+use crate::acrobits::{AcrobitsSMS, Attachment};
 use crate::errors::VoipBitsError;
+use crate::logging;
+use crate::phone::{Country, PhoneNumber};
+use crate::provider::{Sent, SipAccount, SmsProvider};
+use crate::segment;
 use crate::Opt;
 use anyhow::Error;
-use chrono::{DateTime, Duration, NaiveDateTime, Timelike, Utc};
-use chrono_tz::US::Pacific;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use fehler::{throw, throws};
+use futures::stream::{self, StreamExt};
 use maplit::hashmap;
-use regex::Regex;
-use reqwest::Client;
-use rsa::{PaddingScheme, RSAPrivateKey};
-use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
-use serde_json::{from_str, Value};
+use reqwest::{header, Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_json::{from_str, from_value, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::str;
-use tracing::{error, info};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration as StdDuration;
+use tracing::{debug, error, info, warn};
 
-const VOIPMS_URL: &'static str = "https://www.voip.ms/api/v1/rest.php";
+pub const VOIPMS_API_URL: &str = "https://www.voip.ms/api/v1/rest.php";
+
+/// sendMMS takes at most this many media per message
+const MAX_MMS_MEDIA: usize = 3;
+
+/// How many attachments are looked up on the media server at once
+const MEDIA_HEAD_CONCURRENCY: usize = 8;
+
+/// Messages per getSMS/getMMS request when syncing
+const PAGE_SIZE: usize = 50;
+
+/// The most getSMS/getMMS give at once
+const MAX_LIMIT: usize = 9999;
+
+/// How many message dates of a DID `SMS_DATES` keeps, dropping the oldest ones first
+const CACHED_DATES_PER_DID: usize = 50;
+
+/// Beyond this many DIDs in `SMS_DATES`, the least recently active one is dropped
+const MAX_CACHED_DIDS: usize = 1_000;
+
+/// Dates of the messages already fetched, by DID and id, so that polling with the last
+/// known id does not have to look it up on voip.ms again. Only the newest messages of
+/// each DID are kept, since polling goes by the last one.
+static SMS_DATES: Mutex<BTreeMap<String, BTreeMap<u64, DateTime<Utc>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// How many times a message is sent before giving up while voip.ms is unavailable
+const SEND_ATTEMPTS: u32 = 3;
+
+/// The wait before the second attempt, doubling after each
+const RETRY_BACKOFF: StdDuration = StdDuration::from_millis(500);
+
+/// Dialed to reach the voicemail of the account
+const VOICEMAIL_NUMBER: &str = "*97";
 
 pub struct VoipMS {
     user: String,
     key: String,
     pub did: String,
+    /// The timezone of the account, which getSMS gives its dates in
+    timezone: Tz,
+    /// The country of national destination numbers
+    country: Country,
+    /// Whether the account can send SMS outside North America
+    international_sms: bool,
+    /// Whether long texts get `(i/n)` markers
+    segment_markers: bool,
+    /// The REST endpoint, see `VOIPMS_API_URL`
+    api_url: String,
     client: Client,
 }
 
 impl VoipMS {
-    #[throws(Error)]
-    pub fn from_cred(priv_key: &str, cred: &str) -> VoipMS {
-        let cred = cred.replace(" ", "+");
-        let priv_key = RSAPrivateKey::from_pkcs8(&base64::decode(priv_key)?)?;
-        let cred = priv_key.decrypt(
-            PaddingScheme::new_pkcs1v15_encrypt(),
-            &base64::decode(cred)?,
-        )?;
-
-        let cred = String::from_utf8_lossy(&cred);
-        let creds: Vec<_> = cred.split(":").collect();
-        match creds.as_slice() {
-            [did, username, password] => VoipMS::new(&username, &password, did),
-            _ => unreachable!(),
-        }
-    }
-
-    pub fn new(user: &str, key: &str, did: &str) -> VoipMS {
+    pub fn new(
+        user: &str,
+        key: &str,
+        did: &str,
+        timezone: Tz,
+        country: Country,
+        international_sms: bool,
+        segment_markers: bool,
+    ) -> VoipMS {
         Self {
             user: user.into(),
             key: key.into(),
             did: did.into(),
+            timezone,
+            country,
+            international_sms,
+            segment_markers,
+            api_url: VOIPMS_API_URL.into(),
             client: Client::new(),
         }
     }
 
+    pub fn with_api_url(mut self, api_url: &str) -> VoipMS {
+        self.api_url = api_url.into();
+        self
+    }
+
+    /// The `dst` of sendSMS: 10 digits in North America, short codes there as they are,
+    /// and the number with its country code elsewhere if the account allows it.
+    #[throws(Error)]
+    fn destination(&self, dst: &str) -> String {
+        let number = PhoneNumber::parse(dst, self.country)?;
+        match &number {
+            PhoneNumber::ShortCode { country, digits } if country.is_nanp() => digits.clone(),
+            PhoneNumber::ShortCode { country, .. } => {
+                throw!(VoipBitsError::UndeliverableNumber(format!(
+                    "{}: voip.ms only reaches short codes in North America, not in {}",
+                    dst, country.iso
+                )))
+            }
+            PhoneNumber::E164(digits) => match number.nanp_national() {
+                Some(national) => national.into(),
+                None if self.international_sms => digits.clone(),
+                None => throw!(VoipBitsError::UndeliverableNumber(format!(
+                    "{}: international SMS is not enabled for this deployment",
+                    dst
+                ))),
+            },
+        }
+    }
+
     #[throws(Error)]
     pub async fn request<'a, T, O>(&'a self, params: T) -> O
     where
@@ -63,57 +139,130 @@ impl VoipMS {
 
         qs.extend(params);
 
-        let resp = self.client.get(VOIPMS_URL).query(&qs).send().await?;
+        let resp = self.client.get(&self.api_url).query(&qs).send().await?;
         let status = resp.status();
         let payload = resp.text().await?;
 
-        if !status.is_success() {
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            error!("Response: ({}) {}", status, payload);
+            throw!(VoipBitsError::Unavailable(format!("HTTP {}", status)));
+        } else if !status.is_success() {
             error!("Response: ({}) {}", status, payload);
+            throw!(VoipBitsError::Upstream(format!("HTTP {}", status)));
         } else {
-            info!("Response: ({}) {}", status, payload);
+            info!("Response: ({}) {} bytes", status, payload.len());
+            if logging::sensitive() {
+                debug!("Response payload: {}", payload);
+            }
+        }
+
+        let payload: Value = from_str(&payload)
+            .map_err(|e| VoipBitsError::Upstream(format!("unparsable response: {}", e)))?;
+        match payload.get("status").and_then(Value::as_str) {
+            // `no_sms` is what getSMS answers when the date range is empty, likewise for getMMS
+            Some("success") | Some("no_sms") | Some("no_mms") => {}
+            Some(status) => throw!(VoipBitsError::Upstream(status.into())),
+            None => throw!(VoipBitsError::Upstream("response without status".into())),
         }
 
-        from_str(&payload)?
+        from_value(payload)
+            .map_err(|e| VoipBitsError::Upstream(format!("unexpected response: {}", e)))?
     }
 
+    /// `request` for the methods that send, attempted again with backoff while voip.ms
+    /// cannot be reached or is overloaded. Timeouts are not retried, the message may have
+    /// gone out.
     #[throws(Error)]
-    #[tracing::instrument(skip(self))]
+    async fn send_request<'a, O>(&'a self, params: HashMap<&'a str, &'a str>) -> O
+    where
+        O: DeserializeOwned,
+    {
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 1;
+        let resp = loop {
+            match self.request(params.clone()).await {
+                Err(e) if attempt < SEND_ATTEMPTS && is_transient(&e) => {
+                    warn!(
+                        "[Voip.ms] Attempt {} failed, retrying in {:?}: {}",
+                        attempt, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                resp => break resp,
+            }
+        };
+        resp?
+    }
+
+    #[throws(Error)]
+    #[tracing::instrument(skip(self, msg))]
     pub async fn send_sms(&self, dst: &str, msg: &str) -> Vec<String> {
-        // Clean up number and message text
-        let re = Regex::new(r"\D").unwrap();
-        let mut dst = re.replace_all(dst, "").to_owned().to_string();
-        let mut msg = msg.trim();
-        // Remove leading '1' on 11-digit phone numbers
-        if dst.len() == 11 {
-            dst = dst.trim_start_matches('1').to_string();
-        }
+        let dst = self.destination(dst)?;
+        let msg = msg.trim();
 
-        // Validate destination number and message text
-        if dst.len() != 10 {
-            throw!(VoipBitsError::InvalidNumber(dst.into()));
-        }
-        if msg.len() == 0 {
+        // Validate message text
+        if msg.is_empty() {
             throw!(VoipBitsError::EmptyMessage);
         }
 
-        // Recursively send 160 character chunks
-        let mut ids = vec![]; // sent message ids
-        while msg.len() != 0 {
-            let mut end = msg.len().min(160);
-            while !msg.is_char_boundary(end) {
-                end = end.checked_sub(1).expect("end underflow");
-            }
+        // sendSMS takes a single SMS, longer texts go as several
+        let segments = segment::split(msg, self.segment_markers);
+        info!(
+            "Sending {} {:?} segments",
+            segments.parts.len(),
+            segments.encoding
+        );
 
-            info!("Sending piece {}", &msg[..end]);
+        let mut ids = vec![]; // sent message ids
+        for part in &segments.parts {
+            info!("Sending piece {}", logging::text(part));
             let resp: VoipSendSMSResponse = self
-                .request(hashmap! {
+                .send_request(hashmap! {
                     "method" => "sendSMS",
                     "dst" => &dst,
-                    "message" => &msg[..end]
+                    "message" => part
                 })
                 .await?;
             ids.push(resp.sms.to_string());
-            msg = &msg[end..];
+        }
+        ids
+    }
+
+    /// Sends the media (URLs) with the message as MMS, in batches of `MAX_MMS_MEDIA`.
+    /// The text goes with the first batch.
+    #[throws(Error)]
+    #[tracing::instrument(skip(self, msg))]
+    pub async fn send_mms(&self, dst: &str, msg: &str, media: &[String]) -> Vec<String> {
+        if media.is_empty() {
+            return self.send_sms(dst, msg).await?;
+        }
+
+        let dst = self.destination(dst)?;
+        let msg = msg.trim();
+
+        let mut ids = vec![];
+        for (i, batch) in media.chunks(MAX_MMS_MEDIA).enumerate() {
+            let text = if i == 0 { msg } else { "" };
+
+            let mut params = hashmap! {
+                "method" => "sendMMS",
+                "dst" => dst.as_str(),
+                "message" => text,
+            };
+            let keys = ["media1", "media2", "media3"];
+            for (key, url) in keys.iter().zip(batch) {
+                params.insert(key, url);
+            }
+
+            info!(
+                "Sending MMS with {} media and text {}",
+                batch.len(),
+                logging::text(text)
+            );
+            let resp: VoipSendMMSResponse = self.send_request(params).await?;
+            ids.push(resp.mms.to_string());
         }
         ids
     }
@@ -121,69 +270,167 @@ impl VoipMS {
     #[throws(Error)]
     #[tracing::instrument(skip(self))]
     pub async fn fetch_sms_after_id(&self, id: &str) -> Vec<AcrobitsSMS> {
-        let resp: VoipGetSMSResponse = self
-            .request(hashmap! {
-                "method" => "getSMS",
-                "sms" => id,
+        let last_id = sms_id(id).ok_or_else(|| VoipBitsError::NoSuchSMS(id.into()))?;
+        let date = match cached_date(&self.did, last_id) {
+            Some(date) => date,
+            None => self.get_sms_date(id).await?,
+        };
+        info!("[Voip.ms] Date of SMS {}: {}", id, date);
+
+        let mut smss = self.fetch_sms(date, Some(last_id)).await?;
+        smss.retain(|sms| sms_id(&sms.sms_id) > Some(last_id));
+        smss
+    }
+
+    /// Looks up the date of a message, which could be either an SMS or an MMS.
+    #[throws(Error)]
+    async fn get_sms_date(&self, id: &str) -> DateTime<Utc> {
+        let params = |method, key| {
+            hashmap! {
+                "method" => method,
+                key => id,
                 "limit" => "1",
-                "timezone" => if is_dst() { "-1" } else { "0" },
-            })
-            .await?;
+            }
+        };
 
-        if matches!(resp.sms, None) {
-            return vec![];
+        let mut resp: VoipGetSMSResponse = self.request(params("getSMS", "sms")).await?;
+        if resp.sms.as_ref().map(Vec::is_empty).unwrap_or(true) {
+            resp = self.request(params("getMMS", "mms")).await?;
         }
 
-        let date = match resp.sms.unwrap().as_slice() {
+        match resp.sms.unwrap_or_default().as_slice() {
             [] => throw!(VoipBitsError::NoSuchSMS(id.into())),
-            [sms] => sms.date,
-            [..] => unreachable!("Multiple SMS with same ID"),
-        };
-
-        info!("[Voip.ms] Date of SMS {}: {}", id, date);
-        self.fetch_sms_from_date(Some(date)).await?
+            [sms] => account_time(self.timezone, sms.date),
+            [..] => throw!(VoipBitsError::Upstream(format!(
+                "multiple SMS with id {}",
+                id
+            ))),
+        }
     }
 
     #[throws(Error)]
     #[tracing::instrument(skip(self))]
     pub async fn fetch_sms_from_date(&self, from: Option<DateTime<Utc>>) -> Vec<AcrobitsSMS> {
-        // Query voip.ms for received SMS messages ranging from 90 days ago to tomorrow
-        let mut from = from.unwrap_or_else(|| Utc::now() - Duration::days(90));
-        if from < Utc::now() - Duration::days(90) {
-            from = Utc::now() - Duration::days(90)
+        let from = from.unwrap_or_else(|| Utc::now() - Duration::days(90));
+        self.fetch_sms(from, None).await?
+    }
+
+    /// SMS and MMS from the day of `from` on (voip.ms only filters by day), in id order.
+    /// Paging stops at the first message not after `after`.
+    #[throws(Error)]
+    async fn fetch_sms(&self, from: DateTime<Utc>, after: Option<u64>) -> Vec<AcrobitsSMS> {
+        // voip.ms keeps 90 days of messages
+        let from = from.max(Utc::now() - Duration::days(90));
+        let from = from
+            .with_timezone(&self.timezone)
+            .format("%Y-%m-%d")
+            .to_string();
+
+        let mut messages = vec![];
+        for method in &["getSMS", "getMMS"] {
+            messages.extend(self.fetch_pages(method, &from, after).await?);
         }
-        let from = from.format("%Y-%m-%d").to_string();
 
-        let to = (Utc::now() + Duration::days(1))
+        // Messages without media may show up in both lists
+        messages.sort_by_key(|sms| sms_id(&sms.id));
+        messages.dedup_by(|a, b| a.id == b.id);
+        cache_dates(&self.did, self.timezone, &messages);
+
+        let mut smss = messages
+            .into_iter()
+            .map(|vsms| vsms.to_acrobits_reply(self.timezone))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.describe_attachments(&mut smss).await;
+        smss
+    }
+
+    /// Pages through `method` newest first, moving the end of the date range back to
+    /// the oldest message of each page. Pages overlap on that day, the caller dedups.
+    /// The days are in the account timezone.
+    #[throws(Error)]
+    async fn fetch_pages(&self, method: &str, from: &str, after: Option<u64>) -> Vec<VoipSMS> {
+        let mut to = (Utc::now().with_timezone(&self.timezone) + Duration::days(1))
             .format("%Y-%m-%d")
             .to_string();
+        let mut limit = PAGE_SIZE;
+
+        let mut messages = vec![];
+        loop {
+            info!("{} from {} to {}, limit {}", method, from, to, limit);
+            let limit_param = limit.to_string();
+            let resp: VoipGetSMSResponse = self
+                .request(hashmap! {
+                    "method" => method,
+                    "from" => from,
+                    "to" => &to,
+                    "limit" => &limit_param,
+                })
+                .await?;
+            let page = resp.sms.unwrap_or_default();
 
-        info!("Getting SMS from {} to {}", from, to);
+            let full = page.len() >= limit;
+            let oldest = page.iter().min_by_key(|sms| (sms.date, sms_id(&sms.id)));
+            let caught_up = match (after, oldest) {
+                (Some(after), Some(oldest)) => sms_id(&oldest.id) <= Some(after),
+                _ => false,
+            };
+            let next_to = oldest.map(|sms| sms.date.format("%Y-%m-%d").to_string());
+            messages.extend(page);
 
-        let resp: VoipGetSMSResponse = self
-            .request(hashmap! {
-                "method" => "getSMS",
-                "from" => &from,
-                "to" => &to,
-                "limit" => "9999",
-                "timezone" => if is_dst() { "-1" } else { "0" },
-            })
-            .await?;
-        if resp.sms.is_none() {
-            return vec![];
+            if !full || caught_up {
+                break;
+            }
+            match next_to {
+                Some(next_to) if next_to != to => to = next_to,
+                // A whole page on a single day, the range cannot get narrower
+                _ if limit < MAX_LIMIT => limit = MAX_LIMIT,
+                _ => {
+                    warn!("More than {} messages up to {}", MAX_LIMIT, to);
+                    break;
+                }
+            }
         }
+        messages
+    }
 
-        resp.sms
-            .unwrap()
-            .into_iter()
-            .map(|vsms| vsms.to_acrobits_reply())
-            .collect::<Result<_, _>>()?
+    /// Fills in the content type and the size of the attachments from the media server,
+    /// a few attachments at a time.
+    async fn describe_attachments(&self, smss: &mut [AcrobitsSMS]) {
+        let attachments = smss.iter_mut().flat_map(|sms| sms.attachments.iter_mut());
+
+        stream::iter(attachments)
+            .for_each_concurrent(MEDIA_HEAD_CONCURRENCY, |attachment| async move {
+                let resp = self
+                    .client
+                    .head(&attachment.content_url)
+                    .timeout(StdDuration::from_secs(5))
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status());
+                let resp = match resp {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        warn!("Cannot describe media {}: {}", attachment.content_url, e);
+                        return;
+                    }
+                };
+
+                if let Some(content_type) = resp
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                {
+                    attachment.content_type = content_type.into();
+                }
+                attachment.content_size = resp.content_length();
+            })
+            .await;
     }
 
     #[throws(Error)]
     #[tracing::instrument(skip(self, opt))]
     pub async fn set_sms_callback(&self, opt: &Opt) {
-        let url = opt.notify_url();
+        let url = opt.notify_url(&self.did);
 
         let _: Value = self
             .request(hashmap! {
@@ -198,6 +445,130 @@ impl VoipMS {
     }
 }
 
+impl VoipMS {
+    /// The DID as set up on the account, failing when it is not on the account.
+    #[throws(Error)]
+    async fn get_did_info(&self) -> VoipDIDInfo {
+        let resp: VoipGetDIDsInfoResponse = self
+            .request(hashmap! {
+                "method" => "getDIDsInfo",
+            })
+            .await?;
+        match resp.dids.into_iter().find(|did| did.did == self.did) {
+            Some(did) => did,
+            None => throw!(VoipBitsError::Upstream(format!(
+                "{} is not on this account",
+                self.did
+            ))),
+        }
+    }
+
+    /// The sub account the DID is routed to. Calls to the main account cannot be
+    /// provisioned since its SIP password is not available through the API.
+    #[throws(Error)]
+    #[tracing::instrument(skip(self))]
+    pub async fn get_sip_account(&self, pop: Option<&str>) -> Option<SipAccount> {
+        let did = self.get_did_info().await?;
+
+        let account = match did.routing.strip_prefix("account:") {
+            Some(account) => account,
+            None => {
+                info!(
+                    "{} is routed to {}, not provisioning SIP",
+                    self.did, did.routing
+                );
+                return None;
+            }
+        };
+        let resp: VoipGetSubAccountsResponse = match self
+            .request(hashmap! {
+                "method" => "getSubAccounts",
+                "account" => account,
+            })
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!("No sub account {} to provision: {}", account, e);
+                return None;
+            }
+        };
+        let sub_account = match resp.accounts.into_iter().find(|a| a.account == account) {
+            Some(sub_account) => sub_account,
+            None => return None,
+        };
+
+        let domain = match pop {
+            Some(pop) if pop.contains('.') => pop.to_string(),
+            Some(pop) => format!("{}.voip.ms", pop),
+            None => {
+                let resp: VoipGetServersInfoResponse = self
+                    .request(hashmap! {
+                        "method" => "getServersInfo",
+                        "server_pop" => did.pop.as_str(),
+                    })
+                    .await?;
+                match resp.servers.into_iter().next() {
+                    Some(server) => server.server_hostname,
+                    None => throw!(VoipBitsError::Upstream(format!(
+                        "no server for POP {}",
+                        did.pop
+                    ))),
+                }
+            }
+        };
+
+        Some(SipAccount {
+            username: sub_account.account,
+            password: sub_account.password,
+            domain,
+            voicemail_number: Some(VOICEMAIL_NUMBER.into()),
+        })
+    }
+}
+
+#[async_trait]
+impl SmsProvider for VoipMS {
+    fn did(&self) -> &str {
+        &self.did
+    }
+
+    async fn send(&self, dst: &str, msg: &str, media: &[String]) -> Result<Sent, Error> {
+        let ids = if media.is_empty() {
+            self.send_sms(dst, msg).await?
+        } else {
+            self.send_mms(dst, msg, media).await?
+        };
+
+        // Each sendSMS or sendMMS is billed on its own
+        let segments = ids.len();
+        Ok(Sent { ids, segments })
+    }
+
+    async fn fetch_after_id(&self, last_id: &str) -> Result<Vec<AcrobitsSMS>, Error> {
+        self.fetch_sms_after_id(last_id).await
+    }
+
+    async fn fetch_from_date(
+        &self,
+        from: Option<DateTime<Utc>>,
+    ) -> Result<Vec<AcrobitsSMS>, Error> {
+        self.fetch_sms_from_date(from).await
+    }
+
+    async fn verify(&self) -> Result<(), Error> {
+        self.get_did_info().await.map(|_| ())
+    }
+
+    async fn register_callback(&self, opt: &Opt) -> Result<(), Error> {
+        self.set_sms_callback(opt).await
+    }
+
+    async fn sip_account(&self, pop: Option<&str>) -> Result<Option<SipAccount>, Error> {
+        self.get_sip_account(pop).await
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct VoipSendSMSResponse {
@@ -205,6 +576,13 @@ struct VoipSendSMSResponse {
     sms: i64,
 }
 
+#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+struct VoipSendMMSResponse {
+    status: String,
+    mms: i64,
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct VoipGetSMSResponse {
@@ -212,38 +590,76 @@ struct VoipGetSMSResponse {
     sms: Option<Vec<VoipSMS>>,
 }
 
+#[derive(Deserialize, Debug)]
+struct VoipDIDInfo {
+    did: String,
+    /// e.g. `account:100000_sub`, `fwd:1234` or `vm:101`
+    routing: String,
+    #[serde(deserialize_with = "deserialize_voip_string")]
+    pop: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct VoipGetDIDsInfoResponse {
+    dids: Vec<VoipDIDInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VoipSubAccount {
+    account: String,
+    password: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct VoipGetSubAccountsResponse {
+    accounts: Vec<VoipSubAccount>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VoipServer {
+    server_hostname: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct VoipGetServersInfoResponse {
+    servers: Vec<VoipServer>,
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct VoipSMS {
     id: String,
+    /// In the account timezone, see `account_time`
     #[serde(deserialize_with = "deserialize_voip_datetime")]
-    date: DateTime<Utc>,
+    date: NaiveDateTime,
     r#type: String,
     did: String,
     contact: String,
     message: Option<String>,
-}
-
-#[derive(Serialize, Debug)]
-pub struct AcrobitsSMS {
-    pub sms_id: String,
-    pub sending_date: DateTime<Utc>,
-    #[serde(skip_serializing_if = "std::option::Option::is_none")]
-    pub sender: Option<String>,
-    #[serde(skip_serializing_if = "std::option::Option::is_none")]
-    pub recipient: Option<String>,
-    pub sms_text: String,
+    /// Only present in getMMS responses
+    #[serde(default, deserialize_with = "deserialize_voip_media")]
+    media: Vec<String>,
 }
 
 impl VoipSMS {
     #[throws(Error)]
-    pub fn to_acrobits_reply(&self) -> AcrobitsSMS {
+    pub fn to_acrobits_reply(&self, timezone: Tz) -> AcrobitsSMS {
         let mut ret = AcrobitsSMS {
             sms_id: self.id.clone(),
-            sending_date: self.date,
+            sending_date: account_time(timezone, self.date),
             sender: None,
             recipient: None,
             sms_text: self.message.clone().unwrap_or_else(|| "".into()),
+            attachments: self
+                .media
+                .iter()
+                .map(|url| Attachment {
+                    content_type: guess_content_type(url).into(),
+                    content_url: url.clone(),
+                    content_size: None,
+                    filename: url.rsplit('/').next().map(Into::into),
+                })
+                .collect(),
         };
 
         match self.r#type.as_str() {
@@ -255,28 +671,445 @@ impl VoipSMS {
                 // received
                 ret.sender = Some(self.contact.clone());
             }
-            _ => unreachable!(),
+            t => throw!(VoipBitsError::Upstream(format!(
+                "unknown SMS type {} for SMS {}",
+                t, self.id
+            ))),
         }
 
         ret
     }
 }
 
-pub fn deserialize_voip_datetime<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+pub fn deserialize_voip_datetime<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
-    let ndt = NaiveDateTime::parse_from_str(&s, "%F %T").map_err(serde::de::Error::custom)?;
-    Ok(DateTime::<Utc>::from_utc(ndt, Utc))
+    NaiveDateTime::parse_from_str(&s, "%F %T").map_err(serde::de::Error::custom)
+}
+
+/// Turns a wall clock time of the account timezone into an instant.
+///
+/// When the clock falls back, the repeated hour is taken as the first one. Times skipped
+/// when it springs forward do not happen on the voip.ms side, but would be read with
+/// the offset from before the change.
+pub fn account_time(timezone: Tz, time: NaiveDateTime) -> DateTime<Utc> {
+    let local = match timezone.from_local_datetime(&time).earliest() {
+        Some(local) => local,
+        None => {
+            let hour = Duration::hours(1);
+            match timezone.from_local_datetime(&(time - hour)).earliest() {
+                Some(before) => before + hour,
+                None => return Utc.from_utc_datetime(&time),
+            }
+        }
+    };
+
+    local.with_timezone(&Utc)
+}
+
+/// getMMS gives the media as a list of URLs. Empty entries are dropped.
+fn deserialize_voip_media<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let media = Option::<Vec<Value>>::deserialize(deserializer)?.unwrap_or_default();
+    Ok(media
+        .into_iter()
+        .filter_map(|url| match url {
+            Value::String(url) if !url.is_empty() => Some(url),
+            _ => None,
+        })
+        .collect())
+}
+
+/// voip.ms gives ids either as strings or as numbers.
+fn deserialize_voip_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        v => Err(serde::de::Error::custom(format!(
+            "expected a string, got {}",
+            v
+        ))),
+    }
+}
+
+/// Used until the media server tells us the real content type
+pub fn guess_content_type(url: &str) -> &'static str {
+    let ext = url.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "mp4" => "video/mp4",
+        "3gp" => "video/3gpp",
+        "mp3" => "audio/mpeg",
+        "amr" => "audio/amr",
+        "vcf" => "text/vcard",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether the request failed before voip.ms could take it, so that sending it again
+/// cannot send the message twice.
+fn is_transient(e: &Error) -> bool {
+    match e.downcast_ref::<VoipBitsError>() {
+        Some(e) => matches!(e, VoipBitsError::Unavailable(_)),
+        None => matches!(e.downcast_ref::<reqwest::Error>(), Some(e) if e.is_connect()),
+    }
 }
 
-fn is_dst() -> bool {
-    let now = Utc::now();
-    let mut diff = now.with_timezone(&Pacific).hour() as i32 - now.hour() as i32;
-    if diff > 0 {
-        diff = diff - 24;
+/// voip.ms ids are increasing numbers, which do not compare as strings.
+fn sms_id(id: &str) -> Option<u64> {
+    id.parse().ok()
+}
+
+fn cached_date(did: &str, id: u64) -> Option<DateTime<Utc>> {
+    let dates = SMS_DATES.lock().unwrap_or_else(PoisonError::into_inner);
+    dates.get(did).and_then(|dates| dates.get(&id)).copied()
+}
+
+fn cache_dates(did: &str, timezone: Tz, messages: &[VoipSMS]) {
+    let dates = messages
+        .iter()
+        .filter_map(|sms| sms_id(&sms.id).map(|id| (id, account_time(timezone, sms.date))));
+    let mut cache = SMS_DATES.lock().unwrap_or_else(PoisonError::into_inner);
+    insert_dates(&mut cache, did, dates);
+}
+
+fn insert_dates(
+    cache: &mut BTreeMap<String, BTreeMap<u64, DateTime<Utc>>>,
+    did: &str,
+    dates: impl IntoIterator<Item = (u64, DateTime<Utc>)>,
+) {
+    let did_dates = cache.entry(did.to_string()).or_default();
+    did_dates.extend(dates);
+    while did_dates.len() > CACHED_DATES_PER_DID {
+        did_dates.pop_first();
     }
 
-    diff == -7
+    if cache.len() > MAX_CACHED_DIDS {
+        let idle = cache
+            .iter()
+            .filter(|(cached, _)| cached.as_str() != did)
+            .min_by_key(|(_, dates)| dates.values().max().copied())
+            .map(|(did, _)| did.clone());
+        if let Some(idle) = idle {
+            cache.remove(&idle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Query, routing::get, Json, Router};
+    use chrono::NaiveDate;
+    use chrono_tz::{America::New_York, UTC};
+    use serde_json::{json, Value};
+    use std::cmp::Reverse;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    fn utc(time: NaiveDateTime) -> String {
+        account_time(New_York, time).to_rfc3339()
+    }
+
+    fn naive(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%F %T").unwrap()
+    }
+
+    #[test]
+    fn spring_forward() {
+        // 2022-03-13 02:00 EST became 03:00 EDT
+        assert_eq!(
+            utc(naive("2022-03-13 01:59:59")),
+            "2022-03-13T06:59:59+00:00"
+        );
+        assert_eq!(
+            utc(naive("2022-03-13 03:00:00")),
+            "2022-03-13T07:00:00+00:00"
+        );
+        // Skipped, read as EST
+        assert_eq!(
+            utc(naive("2022-03-13 02:30:00")),
+            "2022-03-13T07:30:00+00:00"
+        );
+    }
+
+    #[test]
+    fn fall_back() {
+        // 2022-11-06 02:00 EDT became 01:00 EST
+        assert_eq!(
+            utc(naive("2022-11-06 00:59:59")),
+            "2022-11-06T04:59:59+00:00"
+        );
+        // Repeated, read as EDT
+        assert_eq!(
+            utc(naive("2022-11-06 01:30:00")),
+            "2022-11-06T05:30:00+00:00"
+        );
+        assert_eq!(
+            utc(naive("2022-11-06 02:00:00")),
+            "2022-11-06T07:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn sending_date() {
+        let sms: VoipSMS = serde_json::from_str(
+            r#"{"id": "111", "date": "2022-07-01 09:15:00", "type": "1", "did": "5145550000", "contact": "5145551111", "message": "hi"}"#,
+        )
+        .unwrap();
+        let sms = sms.to_acrobits_reply(New_York).unwrap();
+
+        assert_eq!(sms.sending_date.to_rfc3339(), "2022-07-01T13:15:00+00:00");
+        assert_eq!(sms.sender.as_deref(), Some("5145551111"));
+    }
+
+    type Requests = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    /// getSMS over `messages`, by id or by day.
+    fn get_sms(messages: &[(u64, NaiveDateTime)], query: &HashMap<String, String>) -> Value {
+        let mut page: Vec<_> = match (query["method"].as_str(), query.get("sms")) {
+            ("getSMS", Some(id)) => messages
+                .iter()
+                .filter(|(sms, _)| sms.to_string() == *id)
+                .collect(),
+            ("getSMS", None) => {
+                let day = |param: &str| NaiveDate::parse_from_str(&query[param], "%F").unwrap();
+                let (from, to) = (day("from"), day("to"));
+                messages
+                    .iter()
+                    .filter(|(_, date)| from <= date.date() && date.date() <= to)
+                    .collect()
+            }
+            _ => vec![],
+        };
+        page.sort_by_key(|(id, date)| Reverse((*date, *id)));
+        let limit: usize = query
+            .get("limit")
+            .map_or(Ok(PAGE_SIZE), |l| l.parse())
+            .unwrap();
+        page.truncate(limit);
+
+        let sms: Vec<_> = page
+            .into_iter()
+            .map(|(id, date)| {
+                json!({
+                    "id": id.to_string(),
+                    "date": date.format("%F %T").to_string(),
+                    "type": "1",
+                    "did": query["did"],
+                    "contact": "5145550000",
+                    "message": format!("text {}", id),
+                })
+            })
+            .collect();
+        let status = match (sms.is_empty(), query["method"].as_str()) {
+            (false, _) => "success",
+            (true, "getSMS") => "no_sms",
+            (true, _) => "no_mms",
+        };
+        json!({ "status": status, "sms": sms })
+    }
+
+    /// The only DID on the account of `mock_voipms`
+    const ON_ACCOUNT: &str = "5145559006";
+
+    /// Serves voip.ms getSMS over `messages` (ids and dates in UTC), newest first as
+    /// voip.ms does, and getDIDsInfo. Returns the URL and the log of the requests.
+    async fn mock_voipms(messages: Vec<(u64, NaiveDateTime)>) -> (String, Requests) {
+        let requests = Requests::default();
+        let log = requests.clone();
+        let app = Router::new().route(
+            "/",
+            get(move |Query(query): Query<HashMap<String, String>>| {
+                log.lock().unwrap().push(query.clone());
+                let body = match query["method"].as_str() {
+                    "getDIDsInfo" => json!({
+                        "status": "success",
+                        "dids": [{ "did": ON_ACCOUNT, "routing": "account:100000_sub", "pop": "1" }],
+                    }),
+                    _ => get_sms(&messages, &query),
+                };
+                async move { Json(body) }
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (format!("http://{}/", addr), requests)
+    }
+
+    fn voipms(did: &str, api_url: &str) -> VoipMS {
+        VoipMS::new("me", "key", did, UTC, "US".parse().unwrap(), false, false)
+            .with_api_url(api_url)
+    }
+
+    fn days_ago(days: i64, seconds: i64) -> NaiveDateTime {
+        let midnight = (Utc::now() - Duration::days(days))
+            .naive_utc()
+            .date()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        midnight + Duration::seconds(seconds)
+    }
+
+    fn ids(smss: &[AcrobitsSMS]) -> Vec<u64> {
+        smss.iter().map(|sms| sms.sms_id.parse().unwrap()).collect()
+    }
+
+    /// The getSMS listing requests, without the lookups by id.
+    fn listings(requests: &Requests) -> Vec<HashMap<String, String>> {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|query| query["method"] == "getSMS" && !query.contains_key("sms"))
+            .cloned()
+            .collect()
+    }
+
+    fn lookups(requests: &Requests) -> usize {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|query| query.contains_key("sms"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn after_id() {
+        let messages = (98..=101).map(|id| (id, days_ago(2, id as i64))).collect();
+        let (url, requests) = mock_voipms(messages).await;
+        let voipms = voipms("5145559001", &url);
+
+        // "100" sorts before "99" as a string
+        let smss = voipms.fetch_sms_after_id("99").await.unwrap();
+        assert_eq!(ids(&smss), vec![100, 101]);
+        assert_eq!(lookups(&requests), 1);
+
+        // The date of 101 is cached by the fetch
+        assert!(voipms.fetch_sms_after_id("101").await.unwrap().is_empty());
+        assert_eq!(lookups(&requests), 1);
+
+        assert!(voipms.fetch_sms_after_id("5").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pages() {
+        // 12 messages a day for 10 days, ids in date order
+        let messages = (0..120)
+            .map(|i| (i + 1, days_ago(10 - i as i64 / 12, i as i64 * 60)))
+            .collect();
+        let (url, requests) = mock_voipms(messages).await;
+
+        let smss = voipms("5145559002", &url)
+            .fetch_sms_from_date(None)
+            .await
+            .unwrap();
+        assert_eq!(ids(&smss), (1..=120).collect::<Vec<_>>());
+
+        // The end of the range moves back to the day of the oldest message of each page
+        let ends: Vec<_> = listings(&requests)
+            .iter()
+            .map(|query| query["to"].clone())
+            .collect();
+        assert!(ends.len() > 2);
+        assert!(ends.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", ends);
+    }
+
+    #[tokio::test]
+    async fn single_day() {
+        let messages = (1..=60).map(|id| (id, days_ago(1, id as i64))).collect();
+        let (url, requests) = mock_voipms(messages).await;
+
+        let smss = voipms("5145559003", &url)
+            .fetch_sms_from_date(None)
+            .await
+            .unwrap();
+        assert_eq!(smss.len(), 60);
+
+        // Narrowed down to the day of the messages, then a bigger page as the range
+        // cannot get narrower than a day
+        let limits: Vec<_> = listings(&requests)
+            .iter()
+            .map(|query| query["limit"].clone())
+            .collect();
+        assert_eq!(
+            limits,
+            vec![
+                PAGE_SIZE.to_string(),
+                PAGE_SIZE.to_string(),
+                MAX_LIMIT.to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn short_page() {
+        let messages = (1..=3).map(|id| (id, days_ago(1, id as i64))).collect();
+        let (url, requests) = mock_voipms(messages).await;
+        let smss = voipms("5145559004", &url)
+            .fetch_sms_from_date(None)
+            .await
+            .unwrap();
+        assert_eq!(smss.len(), 3);
+        assert_eq!(listings(&requests).len(), 1);
+
+        let (url, requests) = mock_voipms(vec![]).await;
+        let smss = voipms("5145559005", &url)
+            .fetch_sms_from_date(None)
+            .await
+            .unwrap();
+        assert!(smss.is_empty());
+        assert_eq!(listings(&requests).len(), 1);
+        // And one getMMS
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        let (url, _) = mock_voipms(vec![]).await;
+        assert!(voipms(ON_ACCOUNT, &url).verify().await.is_ok());
+
+        let err = voipms("5145559007", &url).verify().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VoipBitsError>(),
+            Some(VoipBitsError::Upstream(_))
+        ));
+    }
+
+    #[test]
+    fn date_cache_bounds() {
+        let mut cache = BTreeMap::new();
+        let date = |days| Utc::now() - Duration::days(days);
+
+        insert_dates(&mut cache, "1", (1..=60).map(|id| (id, date(1))));
+        let kept: Vec<_> = cache["1"].keys().copied().collect();
+        assert_eq!(kept, (11..=60).collect::<Vec<_>>());
+
+        // The DID of the oldest messages goes first
+        insert_dates(&mut cache, "0", vec![(1, date(30))]);
+        for did in 2..MAX_CACHED_DIDS {
+            insert_dates(&mut cache, &did.to_string(), vec![(1, date(2))]);
+        }
+        assert_eq!(cache.len(), MAX_CACHED_DIDS);
+        insert_dates(&mut cache, "new", vec![(1, date(0))]);
+        assert_eq!(cache.len(), MAX_CACHED_DIDS);
+        assert!(!cache.contains_key("0"));
+        assert!(cache.contains_key("new"));
+    }
 }